@@ -0,0 +1,242 @@
+//! A stand-in for the Sprenkler control unit that keeps its valves in
+//! memory, to run the web server without hardware.
+//!
+//! It answers `PUT /valves/:id` with `open` or `closed` and `GET
+//! /valves/:id` like the real controller. `GET /mock` shows the valves and
+//! the last commands received, `POST /mock/faults` changes the injected
+//! latency and failure rate.
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{sleep, Duration};
+use warp::http::StatusCode;
+use warp::Filter;
+
+type ValveNumber = u8;
+
+/// How many of the last commands are kept.
+const COMMAND_HISTORY: usize = 100;
+
+/// What makes the controller misbehave.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Faults {
+    /// Every request to a valve is answered this late.
+    latency_ms: u64,
+    /// Share of requests to a valve that fail with 503, from 0 to 1.
+    failure_rate: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Command {
+    time: DateTime<Utc>,
+    valve_number: ValveNumber,
+    status: String,
+    /// Whether the controller pretended to fail.
+    failed: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct MockState {
+    /// Valves that weren't switched yet are closed.
+    valves: BTreeMap<ValveNumber, &'static str>,
+    commands: VecDeque<Command>,
+    faults: Faults,
+}
+
+type Mock = Arc<RwLock<MockState>>;
+
+impl MockState {
+    fn new(faults: Faults) -> Self {
+        MockState {
+            valves: BTreeMap::new(),
+            commands: VecDeque::new(),
+            faults,
+        }
+    }
+
+    fn status(&self, valve_number: ValveNumber) -> &'static str {
+        self.valves.get(&valve_number).copied().unwrap_or("closed")
+    }
+
+    fn record(&mut self, command: Command) {
+        if self.commands.len() == COMMAND_HISTORY {
+            self.commands.pop_front();
+        }
+        self.commands.push_back(command);
+    }
+}
+
+/// Waits for the configured latency, then tells whether to fail.
+async fn inject_faults(mock: &Mock) -> bool {
+    let faults = mock.read().await.faults;
+    sleep(Duration::from_millis(faults.latency_ms)).await;
+    rand::thread_rng().gen_bool(faults.failure_rate.clamp(0.0, 1.0))
+}
+
+async fn set_valve(
+    valve_number: ValveNumber,
+    body: warp::hyper::body::Bytes,
+    mock: Mock,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let failed = inject_faults(&mock).await;
+    let body = String::from_utf8_lossy(&body).trim().to_owned();
+    let status = match body.as_str() {
+        "open" => "open",
+        "closed" => "closed",
+        _ => {
+            tracing::warn!("Valve {}: invalid command {:?}", valve_number, body);
+            return Ok(StatusCode::BAD_REQUEST);
+        }
+    };
+    let mut state = mock.write().await;
+    state.record(Command {
+        time: Utc::now(),
+        valve_number,
+        status: status.to_owned(),
+        failed,
+    });
+    if failed {
+        tracing::warn!("Valve {}: failing to set it {}", valve_number, status);
+        return Ok(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    tracing::info!("Valve {}: {}", valve_number, status);
+    state.valves.insert(valve_number, status);
+    Ok(StatusCode::OK)
+}
+
+async fn get_valve(
+    valve_number: ValveNumber,
+    mock: Mock,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if inject_faults(&mock).await {
+        tracing::warn!("Valve {}: failing to report it", valve_number);
+        return Ok(Box::new(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    Ok(Box::new(mock.read().await.status(valve_number)))
+}
+
+async fn show_state(mock: Mock) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&*mock.read().await))
+}
+
+async fn set_faults(faults: Faults, mock: Mock) -> Result<impl warp::Reply, warp::Rejection> {
+    if !(0.0..=1.0).contains(&faults.failure_rate) {
+        return Ok(StatusCode::BAD_REQUEST);
+    }
+    tracing::info!("Injecting {:?}", faults);
+    mock.write().await.faults = faults;
+    Ok(StatusCode::OK)
+}
+
+fn with_mock(
+    mock: Mock,
+) -> impl Filter<Extract = (Mock,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || mock.clone())
+}
+
+fn routes(mock: Mock) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    // The web server's health check, any answer will do
+    let health = warp::get().and(warp::path::end()).map(|| "ok");
+    let set_valve = warp::put()
+        .and(warp::path!("valves" / ValveNumber))
+        .and(warp::body::bytes())
+        .and(with_mock(mock.clone()))
+        .and_then(set_valve);
+    let get_valve = warp::get()
+        .and(warp::path!("valves" / ValveNumber))
+        .and(with_mock(mock.clone()))
+        .and_then(get_valve);
+    let show_state = warp::get()
+        .and(warp::path!("mock"))
+        .and(with_mock(mock.clone()))
+        .and_then(show_state);
+    let set_faults = warp::post()
+        .and(warp::path!("mock" / "faults"))
+        .and(warp::body::json())
+        .and(with_mock(mock))
+        .and_then(set_faults);
+    health
+        .or(set_valve)
+        .or(get_valve)
+        .or(show_state)
+        .or(set_faults)
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            tracing::error!("Invalid {}: {}", name, value);
+            std::process::exit(1);
+        }),
+        Err(_) => default,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| "mock_controller=info".to_owned());
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    let port = env_or("MOCK_PORT", 4040u16);
+    let faults = Faults {
+        latency_ms: env_or("MOCK_LATENCY_MS", 0),
+        failure_rate: env_or("MOCK_FAILURE_RATE", 0.0),
+    };
+    // Also rules out NaN, which `gen_bool` panics on
+    if !(0.0..=1.0).contains(&faults.failure_rate) {
+        tracing::error!("Invalid MOCK_FAILURE_RATE: {}", faults.failure_rate);
+        std::process::exit(1);
+    }
+    let mock = Arc::new(RwLock::new(MockState::new(faults)));
+    tracing::info!("Mock controller on port {} with {:?}", port, faults);
+    warp::serve(routes(mock)).run(([127, 0, 0, 1], port)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{routes, Faults, MockState};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    async fn test_mock_controller() {
+        let faults = Faults {
+            latency_ms: 0,
+            failure_rate: 0.0,
+        };
+        let mock = Arc::new(RwLock::new(MockState::new(faults)));
+        let routes = routes(mock.clone());
+        let request = |method: &str, path: &str, body: &str| {
+            warp::test::request().method(method).path(path).body(body)
+        };
+
+        let response = request("GET", "/valves/3", "").reply(&routes).await;
+        assert_eq!(response.body(), "closed");
+        let response = request("PUT", "/valves/3", "open").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request("GET", "/valves/3", "").reply(&routes).await;
+        assert_eq!(response.body(), "open");
+        let response = request("PUT", "/valves/3", "halb").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = request(
+            "POST",
+            "/mock/faults",
+            r#"{"latency_ms": 0, "failure_rate": 1.0}"#,
+        )
+        .reply(&routes)
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request("PUT", "/valves/3", "closed").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let state = mock.read().await;
+        assert_eq!(state.status(3), "open");
+        assert_eq!(state.commands.len(), 2);
+        assert!(state.commands[1].failed);
+    }
+}
@@ -1,21 +1,55 @@
-use chrono::naive::{NaiveDateTime, NaiveTime};
-use chrono::Datelike;
+use chrono::naive::{NaiveDate, NaiveDateTime, NaiveTime};
 use chrono::Weekday;
+use chrono::{DateTime, Datelike, LocalResult, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
 use reqwest::Url;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::slice::{Iter, IterMut};
+use std::str::FromStr;
 use std::{fmt, sync::Arc};
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 
-use std::collections::HashMap;
+use crate::sun;
+use crate::transport::TransportConfig;
+use crate::weather::{WeatherAdjustment, WeatherDecision};
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 #[derive(Debug)]
 pub enum Error {
-    BeginAfterEnd,
+    EmptyDuration,
     OverlappingDurations,
+    InvalidSeason,
+    InvalidInterval,
     InvalidValveNumber,
+    InvalidValveName,
     MissingDuration,
+    MissingProgramStep,
+    InvalidProgramId,
     Request(reqwest::Error),
+    ControllerStatus(reqwest::StatusCode),
+    InvalidControllerResponse(String),
+    Unreachable(String),
+    InvalidAddress(url::ParseError),
+    InvalidTimezone(String),
+    InvalidTimeOfDay(String),
+    InvalidLocation,
+    InvalidWaterBudget,
+    InvalidLimit,
+    InvalidValveRole,
+    InvalidOverride,
+    InvalidRainDelay,
+    InvalidRainThreshold,
+    InvalidSensorId,
+    InvalidControllerId,
+    /// The controller was removed, it must not be saved again.
+    RemovedController(String),
+    InvalidMoisture,
+    InvalidTransport,
+    Mqtt(String),
+    Io(std::io::Error),
+    Serialization(serde_json::Error),
 }
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Self {
@@ -23,66 +57,506 @@ impl From<reqwest::Error> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serialization(e)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:#?}", self)
+        match self {
+            Self::Request(e) => write!(f, "Request failed: {}", e),
+            Self::ControllerStatus(status) => {
+                write!(f, "Controller responded with {}", status)
+            }
+            Self::InvalidControllerResponse(body) => {
+                write!(f, "Unexpected response from controller: {:?}", body)
+            }
+            Self::Unreachable(e) => write!(f, "Controller unreachable: {}", e),
+            Self::InvalidAddress(e) => write!(f, "Invalid controller address: {}", e),
+            Self::InvalidTimezone(e) => write!(f, "Invalid timezone: {}", e),
+            Self::InvalidTimeOfDay(s) => write!(f, "Invalid time of day: {:?}", s),
+            Self::RemovedController(id) => write!(f, "Controller {} was removed", id),
+            Self::Mqtt(e) => write!(f, "MQTT: {}", e),
+            Self::Io(e) => write!(f, "IO error: {}", e),
+            Self::Serialization(e) => write!(f, "Invalid state: {}", e),
+            _ => write!(f, "{:#?}", self),
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Request(e) => Some(e),
+            Self::InvalidAddress(e) => Some(e),
+            Self::Io(e) => Some(e),
+            Self::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl warp::reject::Reject for Error {}
+
+/// Maps a wall clock time in `tz` to an instant.
+///
+/// Times skipped when the clocks go forward are moved forward by the length
+/// of the gap, so 02:30 on such a day happens at 03:30. Times repeated when
+/// the clocks go back resolve to their first occurrence, so an entry is never
+/// run twice.
+pub fn resolve_local(tz: &Tz, local: &NaiveDateTime) -> DateTime<Tz> {
+    match tz.from_local_datetime(local) {
+        LocalResult::Single(time) => time,
+        LocalResult::Ambiguous(earliest, _) => earliest,
+        LocalResult::None => {
+            // Transitions are months apart, so the offset of the day before
+            // is the one in effect right before the gap
+            let offset = tz
+                .offset_from_utc_datetime(&(*local - chrono::Duration::days(1)))
+                .fix();
+            let utc = *local - chrono::Duration::seconds(offset.local_minus_utc().into());
+            Utc.from_utc_datetime(&utc).with_timezone(tz)
+        }
+    }
+}
+
+/// Where the controller is, needed for entries relative to the sun.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Location {
+    pub fn new(latitude: f64, longitude: f64) -> Result<Location, Error> {
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            return Err(Error::InvalidLocation);
+        }
+        Ok(Location {
+            latitude,
+            longitude,
+        })
+    }
+
+    /// Sunrise and sunset on `date`, `None` during polar day or night.
+    pub fn sunrise_sunset(&self, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        sun::sunrise_sunset(date, self.latitude, self.longitude)
+    }
+}
+
+/// Controller wide settings the schedules of every valve are evaluated with.
+#[derive(Debug, Copy, Clone)]
+pub struct Environment {
+    pub location: Option<Location>,
+    /// Percentage all run lengths are scaled with.
+    pub water_budget: u32,
+    /// Scheduled watering is suspended until then.
+    pub rain_delay_until: Option<DateTime<Utc>>,
+    /// Only applies on its own date.
+    pub weather: Option<WeatherAdjustment>,
+    /// The wettest recent reading of the sensors at the valve at hand, see
+    /// `ControllerConfig::environment_for`.
+    pub soil_moisture: Option<f64>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment {
+            location: None,
+            water_budget: 100,
+            rain_delay_until: None,
+            weather: None,
+            soil_moisture: None,
+        }
+    }
+}
+
+impl Environment {
+    /// The weather adjustment for `date`, if there is one.
+    fn weather_on(&self, date: NaiveDate) -> Option<WeatherAdjustment> {
+        self.weather.filter(|weather| weather.date == date)
+    }
+
+    /// Whether scheduled watering is suspended at `time`, by a rain delay or
+    /// because the weather skips the day.
+    pub fn is_suspended(&self, time: &DateTime<Tz>) -> bool {
+        self.suspended_until(time).is_some()
+    }
+
+    /// When scheduled watering resumes if it is suspended at `time`.
+    pub fn suspended_until(&self, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let tz = time.timezone();
+        let rain_delay = self
+            .rain_delay_until
+            .filter(|until| *time < *until)
+            .map(|until| until.with_timezone(&tz));
+        let date = time.naive_local().date();
+        let skipped = self
+            .weather_on(date)
+            .filter(|weather| weather.skip)
+            .map(|_| resolve_local(&tz, &date.succ().and_hms(0, 0, 0)));
+        rain_delay.into_iter().chain(skipped).max()
+    }
+
+    /// How long a run of `minutes` started on `date` lasts under the water
+    /// budget and the weather of that day.
+    pub fn run_length(&self, date: NaiveDate, minutes: u32) -> chrono::Duration {
+        let weather = self.weather_on(date).map_or(100, |weather| weather.percent);
+        chrono::Duration::seconds(
+            i64::from(minutes) * 60 * i64::from(self.water_budget) / 100 * i64::from(weather) / 100,
+        )
+    }
+}
+
+/// Water budgets above this percentage are considered typos.
+const MAX_WATER_BUDGET: u32 = 1000;
+
+fn check_water_budget(percent: u32) -> Result<u32, Error> {
+    if percent > MAX_WATER_BUDGET {
+        return Err(Error::InvalidWaterBudget);
+    }
+    Ok(percent)
+}
+
+/// Range-checks a water budget from a state file, the budgets are
+/// multiplied with each other and with run lengths.
+fn water_budget_from<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    check_water_budget(u32::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/// Like `water_budget_from` for budgets that can be left out.
+fn optional_water_budget_from<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u32>::deserialize(deserializer)?
+        .map(check_water_budget)
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Entries can't be moved further away from sunrise or sunset.
+const MAX_SUN_OFFSET_MINUTES: i32 = 6 * 60;
+
+/// One end of a `Duration`, either a fixed wall clock time or a number of
+/// minutes before or after sunrise or sunset.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimeOfDay {
+    Fixed(NaiveTime),
+    Sunrise(i32),
+    Sunset(i32),
+}
+
+impl TimeOfDay {
+    pub fn is_fixed(&self) -> bool {
+        matches!(self, TimeOfDay::Fixed(_))
+    }
+
+    /// The wall clock time this refers to on `date` in the timezone `tz`.
+    /// Times relative to the sun are `None` without a location or if the sun
+    /// doesn't rise or set on that day.
+    pub fn on(
+        &self,
+        date: NaiveDate,
+        tz: &Tz,
+        location: Option<&Location>,
+    ) -> Option<NaiveDateTime> {
+        let (sun_time, offset) = match *self {
+            TimeOfDay::Fixed(time) => return Some(date.and_time(time)),
+            TimeOfDay::Sunrise(offset) => (location?.sunrise_sunset(date)?.0, offset),
+            TimeOfDay::Sunset(offset) => (location?.sunrise_sunset(date)?.1, offset),
+        };
+        let local = sun_time.with_timezone(tz).naive_local();
+        Some(local + chrono::Duration::minutes(offset.into()))
+    }
+}
+
+impl From<NaiveTime> for TimeOfDay {
+    fn from(time: NaiveTime) -> Self {
+        TimeOfDay::Fixed(time)
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (event, offset) = match self {
+            TimeOfDay::Fixed(time) => return write!(f, "{}", time),
+            TimeOfDay::Sunrise(offset) => ("sunrise", offset),
+            TimeOfDay::Sunset(offset) => ("sunset", offset),
+        };
+        match offset {
+            0 => write!(f, "{}", event),
+            offset => write!(f, "{}{:+}", event, offset),
+        }
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = Error;
+
+    /// Parses `06:00`, `06:00:00`, `sunrise`, `sunset+30` or `sunrise-15`,
+    /// offsets are at most `MAX_SUN_OFFSET_MINUTES`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || Error::InvalidTimeOfDay(s.to_string());
+        let relative = |rest: &str| -> Result<i32, Error> {
+            let offset: i32 = match rest {
+                "" => 0,
+                rest if rest.starts_with('+') || rest.starts_with('-') => {
+                    rest.parse().map_err(|_| invalid())?
+                }
+                _ => return Err(invalid()),
+            };
+            if offset.abs() > MAX_SUN_OFFSET_MINUTES {
+                return Err(invalid());
+            }
+            Ok(offset)
+        };
+        if let Some(rest) = s.strip_prefix("sunrise") {
+            return Ok(TimeOfDay::Sunrise(relative(rest)?));
+        }
+        if let Some(rest) = s.strip_prefix("sunset") {
+            return Ok(TimeOfDay::Sunset(relative(rest)?));
+        }
+        NaiveTime::parse_from_str(s, "%H:%M:%S%.f")
+            .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+            .map(TimeOfDay::Fixed)
+            .map_err(|_| invalid())
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A single run, ending either at a time of day or after a number of minutes.
+/// Exactly one of `end` and `run_minutes` is set.
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Duration {
-    begin: NaiveTime,
-    end: NaiveTime,
+    begin: TimeOfDay,
+    #[serde(default)]
+    end: Option<TimeOfDay>,
+    /// Scaled by the water budget, unlike durations with an `end`.
+    #[serde(default)]
+    run_minutes: Option<u32>,
 }
 
 impl Duration {
-    pub fn new(begin: NaiveTime, end: NaiveTime) -> Result<Duration, Error> {
-        if begin >= end {
-            return Err(Error::BeginAfterEnd);
+    /// Creates a new duration, an `end` before `begin` means it runs past midnight.
+    pub fn new(begin: impl Into<TimeOfDay>, end: impl Into<TimeOfDay>) -> Result<Duration, Error> {
+        let (begin, end) = (begin.into(), end.into());
+        if begin == end {
+            return Err(Error::EmptyDuration);
         }
-        Ok(Duration { begin, end })
+        Ok(Duration {
+            begin,
+            end: Some(end),
+            run_minutes: None,
+        })
     }
 
-    pub fn is_overlapping(&self, other: &Self) -> bool {
-        if self.end < other.begin {
-            return false;
+    /// Creates a duration running for `minutes` at full water budget.
+    pub fn with_run_length(begin: impl Into<TimeOfDay>, minutes: u32) -> Result<Duration, Error> {
+        if minutes == 0 {
+            return Err(Error::EmptyDuration);
         }
-        if other.end < self.begin {
-            return false;
+        Ok(Duration {
+            begin: begin.into(),
+            end: None,
+            run_minutes: Some(minutes),
+        })
+    }
+
+    /// Whether both ends are fixed times, only those can be checked for
+    /// overlaps up front as the others move with the seasons.
+    pub fn is_fixed(&self) -> bool {
+        self.begin.is_fixed() && self.end.map_or(true, |end| end.is_fixed())
+    }
+
+    /// The wall clock begin and end of this duration when it is started on
+    /// `date`. If the end isn't after the begin, it is taken from the next day
+    /// when both are fixed. Otherwise the sun has moved past the fixed end
+    /// and the duration doesn't run that day.
+    pub fn on(
+        &self,
+        date: NaiveDate,
+        tz: &Tz,
+        env: &Environment,
+    ) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        let location = env.location.as_ref();
+        let begin = self.begin.on(date, tz, location)?;
+        let end = match (self.end, self.run_minutes) {
+            (Some(end), _) => {
+                let end_time = end.on(date, tz, location)?;
+                if end_time > begin {
+                    end_time
+                } else if self.is_fixed() {
+                    end.on(date.succ(), tz, location)?
+                } else {
+                    return None;
+                }
+            }
+            (None, Some(minutes)) => begin + env.run_length(date, minutes),
+            (None, None) => return None,
+        };
+        Some((begin, end))
+    }
+
+    /// Whether this duration started on `date` overlaps `other` started on
+    /// `other_date` at full water budget. Durations relative to the sun are
+    /// checked on every day of the year of `date` at the site's location, as
+    /// they move across fixed times in spring and autumn. Without a location
+    /// they don't run and so never overlap.
+    pub fn is_overlapping(
+        &self,
+        date: NaiveDate,
+        other: &Self,
+        other_date: NaiveDate,
+        site: &Site,
+    ) -> bool {
+        let env = Environment {
+            location: site.location,
+            ..Environment::default()
+        };
+        let overlaps_on = |date: NaiveDate, other_date: NaiveDate| {
+            let (begin, end) = match self.on(date, &site.timezone, &env) {
+                Some(times) => times,
+                None => return false,
+            };
+            match other.on(other_date, &site.timezone, &env) {
+                Some((other_begin, other_end)) => end >= other_begin && other_end >= begin,
+                None => false,
+            }
+        };
+        if self.is_fixed() && other.is_fixed() {
+            return overlaps_on(date, other_date);
         }
-        true
+        let days = other_date - date;
+        let new_year = NaiveDate::from_ymd(date.year(), 1, 1);
+        (0..366)
+            .map(|offset| new_year + chrono::Duration::days(offset))
+            .any(|day| overlaps_on(day, day + days))
     }
 
-    pub fn contains(&self, other: &NaiveTime) -> bool {
-        &self.begin < other && other < &self.end
+    /// The instants this duration begins and ends at when started on `date`
+    /// in the timezone `tz`, see `resolve_local` for the behaviour around
+    /// daylight saving time transitions.
+    pub fn at(
+        &self,
+        date: NaiveDate,
+        tz: &Tz,
+        env: &Environment,
+    ) -> Option<(DateTime<Tz>, DateTime<Tz>)> {
+        let (begin, end) = self.on(date, tz, env)?;
+        Some((resolve_local(tz, &begin), resolve_local(tz, &end)))
+    }
+}
+/// Where entries are checked for overlaps, entries relative to the sun
+/// depend on both.
+#[derive(Debug, Copy, Clone)]
+pub struct Site {
+    pub timezone: Tz,
+    pub location: Option<Location>,
+}
+
+impl Default for Site {
+    fn default() -> Self {
+        Site {
+            timezone: Tz::UTC,
+            location: None,
+        }
     }
 }
+
+/// Whether any of `entries` collides with `duration` if that is started
+/// `days` days after them. The entries are compared as entered, at a water
+/// budget of 100%. A larger budget can stretch them into each other, that
+/// only keeps the valve open until the later one ends and isn't rejected.
+fn overlapping<'a>(
+    entries: impl IntoIterator<Item = &'a Duration>,
+    duration: &Duration,
+    days: i64,
+    site: &Site,
+) -> bool {
+    // Any date will do for fixed entries, only the distance between them
+    // matters
+    let date = NaiveDate::from_ymd(2000, 1, 1);
+    let other_date = date + chrono::Duration::days(days);
+    entries
+        .into_iter()
+        .any(|d| d.is_overlapping(date, duration, other_date, site))
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct DailySchedule(Vec<Duration>);
 
 impl DailySchedule {
-    pub fn add_entry(&mut self, duration: Duration) -> Result<(), Error> {
-        if self.0.iter().any(|v| v.is_overlapping(&duration)) {
+    pub fn add_entry(&mut self, duration: Duration, site: &Site) -> Result<(), Error> {
+        if self.overlaps(&duration, 0, site) {
             return Err(Error::OverlappingDurations);
         }
         self.0.push(duration);
         Ok(())
     }
     pub fn remove_entry(&mut self, duration: Duration) -> Result<(), Error> {
+        let len = self.0.len();
         self.0.retain(|d| duration != *d);
+        if self.0.len() == len {
+            return Err(Error::MissingDuration);
+        }
         Ok(())
     }
 
-    pub fn should_be_running(&self, time: &NaiveTime) -> bool {
-        self.0.iter().any(|d| d.contains(time))
+    /// Whether `duration` collides with an entry of this schedule, if it is
+    /// started `days` days after the entries of this schedule.
+    pub fn overlaps(&self, duration: &Duration, days: i64, site: &Site) -> bool {
+        overlapping(&self.0, duration, days, site)
+    }
+
+    pub fn entries(&self) -> Iter<'_, Duration> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a DailySchedule {
+    type Item = &'a Duration;
+
+    type IntoIter = Iter<'a, Duration>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries()
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct Schedule(#[serde(serialize_with = "daymap")] HashMap<Weekday, DailySchedule>);
+pub struct Schedule(
+    #[serde(serialize_with = "daymap", deserialize_with = "daymap_from")]
+    HashMap<Weekday, DailySchedule>,
+);
 
 impl Schedule {
     fn empty() -> Self {
@@ -99,6 +573,23 @@ impl Schedule {
     fn insert(&mut self, weekday: Weekday, daily_schedule: DailySchedule) {
         self.0.insert(weekday, daily_schedule);
     }
+
+    /// Adds `duration` to the entries of `day`, making sure it neither
+    /// overlaps the entries spilling over from the day before nor runs into
+    /// the entries of the following day.
+    pub fn add_entry(
+        &mut self,
+        day: &Weekday,
+        duration: Duration,
+        site: &Site,
+    ) -> Result<(), Error> {
+        if self[&day.pred()].overlaps(&duration, 1, site)
+            || self[&day.succ()].overlaps(&duration, -1, site)
+        {
+            return Err(Error::OverlappingDurations);
+        }
+        self[day].add_entry(duration, site)
+    }
 }
 
 impl std::ops::Index<&Weekday> for Schedule {
@@ -115,6 +606,7 @@ impl std::ops::IndexMut<&Weekday> for Schedule {
     }
 }
 
+#[allow(clippy::unnecessary_sort_by)]
 fn daymap<S>(value: &HashMap<Weekday, DailySchedule>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -123,24 +615,281 @@ where
     ordered.sort_by(|a, b| a.0.num_days_from_monday().cmp(&b.0.num_days_from_monday()));
     ordered.serialize(serializer)
 }
-#[derive(Serialize, Deserialize, Debug, Clone)]
+
+/// Counterpart to `daymap`, filling in days missing from the input so that
+/// indexing a `Schedule` by any `Weekday` stays valid.
+fn daymap_from<'de, D>(deserializer: D) -> Result<HashMap<Weekday, DailySchedule>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let ordered: Vec<(Weekday, DailySchedule)> = Vec::deserialize(deserializer)?;
+    let mut schedule = Schedule::empty();
+    for (weekday, daily_schedule) in ordered {
+        schedule.insert(weekday, daily_schedule);
+    }
+    Ok(schedule.0)
+}
+/// The part of the year the recurring schedule of a valve is used in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Season {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    /// Only the day and month are considered, `start` after `end` spans
+    /// the turn of the year.
+    pub yearly: bool,
+}
+
+impl Season {
+    pub fn new(start: NaiveDate, end: NaiveDate, yearly: bool) -> Result<Season, Error> {
+        if !yearly && start > end {
+            return Err(Error::InvalidSeason);
+        }
+        Ok(Season { start, end, yearly })
+    }
+
+    /// Whether `date` lies within the season, both ends included.
+    pub fn contains(&self, date: &NaiveDate) -> bool {
+        if !self.yearly {
+            return &self.start <= date && date <= &self.end;
+        }
+        let day_of_year = |date: &NaiveDate| (date.month(), date.day());
+        let (start, end, date) = (
+            day_of_year(&self.start),
+            day_of_year(&self.end),
+            day_of_year(date),
+        );
+        if start <= end {
+            start <= date && date <= end
+        } else {
+            start <= date || date <= end
+        }
+    }
+}
+
+/// Runs the same entries every `every_days` days, counted from `anchor`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntervalSchedule {
+    #[serde(deserialize_with = "positive_days")]
+    every_days: u16,
+    anchor: NaiveDate,
+    entries: DailySchedule,
+}
+
+/// Rejects an interval of zero days from a state file, `runs_on` divides by it.
+fn positive_days<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let days = u16::deserialize(deserializer)?;
+    if days == 0 {
+        return Err(serde::de::Error::custom("every_days must be at least 1"));
+    }
+    Ok(days)
+}
+
+impl Default for IntervalSchedule {
+    fn default() -> Self {
+        IntervalSchedule {
+            every_days: 1,
+            anchor: NaiveDate::from_ymd(1970, 1, 1),
+            entries: DailySchedule::default(),
+        }
+    }
+}
+
+impl IntervalSchedule {
+    pub fn runs_on(&self, date: &NaiveDate) -> bool {
+        (*date - self.anchor)
+            .num_days()
+            .rem_euclid(self.every_days.into())
+            == 0
+    }
+
+    /// Changes the interval, rejecting entries that would run into the next run.
+    pub fn set_interval(
+        &mut self,
+        every_days: u16,
+        anchor: NaiveDate,
+        site: &Site,
+    ) -> Result<(), Error> {
+        if every_days == 0 {
+            return Err(Error::InvalidInterval);
+        }
+        if every_days == 1
+            && self
+                .entries
+                .entries()
+                .any(|d| self.entries.overlaps(d, 1, site))
+        {
+            return Err(Error::OverlappingDurations);
+        }
+        self.every_days = every_days;
+        self.anchor = anchor;
+        Ok(())
+    }
+
+    pub fn add_entry(&mut self, duration: Duration, site: &Site) -> Result<(), Error> {
+        if self.every_days == 1
+            && (self.entries.overlaps(&duration, 1, site)
+                || self.entries.overlaps(&duration, -1, site))
+        {
+            return Err(Error::OverlappingDurations);
+        }
+        self.entries.add_entry(duration, site)
+    }
+
+    pub fn remove_entry(&mut self, duration: Duration) -> Result<(), Error> {
+        self.entries.remove_entry(duration)
+    }
+}
+
+/// Which recurring schedule a valve follows.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleKind {
+    #[default]
+    Weekly,
+    Interval,
+}
+
+/// Changes to the recurring schedule for specific dates.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Calendar {
+    /// Entries replacing the weekly ones on a date, no entries skip the date.
+    exceptions: BTreeMap<NaiveDate, DailySchedule>,
+    /// Runs in addition to the planned ones on a date.
+    one_offs: BTreeMap<NaiveDate, DailySchedule>,
+}
+
+impl Calendar {
+    pub fn exception(&self, date: &NaiveDate) -> Option<&DailySchedule> {
+        self.exceptions.get(date)
+    }
+
+    pub fn one_offs(&self, date: &NaiveDate) -> Option<&DailySchedule> {
+        self.one_offs.get(date)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum ValveStatus {
     Open,
     Close,
 }
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum AutomationStatus {
     ForceOpen,
     Scheduled,
     ForceClose,
+    /// Open until the given time, then back to the previous mode.
+    ForceOpenUntil(DateTime<Utc>),
+    /// Closed until the given time, then back to the previous mode.
+    ForceCloseUntil(DateTime<Utc>),
+}
+
+impl AutomationStatus {
+    /// When a timed override ends.
+    pub fn until(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Self::ForceOpenUntil(until) | Self::ForceCloseUntil(until) => Some(*until),
+            _ => None,
+        }
+    }
 }
 pub type ValveNumber = u8;
+
+/// What a valve supplies. Master valves and pumps feed the zones, they are
+/// opened whenever a zone is open instead of following a schedule.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValveRole {
+    #[default]
+    Zone,
+    /// Opened shortly before the first zone and closed shortly after the last one.
+    Master,
+    /// Switched on after the zones open and off before they close, so it
+    /// never pumps against closed valves.
+    Pump,
+}
+
+/// How long a master valve opens ahead of the first zone and stays open
+/// after the last one.
+const MASTER_LEAD_SECONDS: i64 = 5;
+
+fn master_lead() -> chrono::Duration {
+    chrono::Duration::seconds(MASTER_LEAD_SECONDS)
+}
+
+/// Outcome of the recent attempts to deliver commands to a valve.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct ValveHealth {
+    pub last_error: Option<String>,
+    pub last_attempt: Option<DateTime<Tz>>,
+    pub consecutive_failures: u32,
+}
+
+impl ValveHealth {
+    pub fn record(&mut self, time: DateTime<Tz>, result: Result<(), Error>) {
+        self.last_attempt = Some(time);
+        match result {
+            Ok(()) => {
+                self.last_error = None;
+                self.consecutive_failures = 0;
+            }
+            Err(e) => {
+                self.last_error = Some(e.to_string());
+                self.consecutive_failures += 1;
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Valve {
     pub name: String,
     pub valve_number: ValveNumber,
-    pub automation_status: AutomationStatus,
+    automation_status: AutomationStatus,
+    /// The mode a timed override returns to.
+    #[serde(default)]
+    previous_automation_status: Option<AutomationStatus>,
     schedule: Schedule,
+    #[serde(default)]
+    pub schedule_kind: ScheduleKind,
+    #[serde(default)]
+    interval_schedule: IntervalSchedule,
+    #[serde(default)]
+    calendar: Calendar,
+    /// When set, the recurring schedule is only used during this season.
+    #[serde(default)]
+    pub season: Option<Season>,
+    /// Percentage run lengths of this valve are scaled with in addition to
+    /// the global water budget.
+    #[serde(default, deserialize_with = "optional_water_budget_from")]
+    water_budget: Option<u32>,
+    #[serde(default)]
+    pub role: ValveRole,
+    /// How long the valve may stay open in one go, overrides the global limit.
+    #[serde(default)]
+    max_open_minutes: Option<u32>,
+    /// Soil moisture in percent from which on scheduled runs are skipped.
+    #[serde(default)]
+    moisture_threshold: Option<f64>,
+    /// The last scheduled run that didn't happen.
+    #[serde(default)]
+    pub last_skip: Option<SkippedRun>,
+    /// When the valve last opened.
+    #[serde(default)]
+    pub last_run: Option<DateTime<Utc>>,
+    /// Since when the executor keeps the valve open. Not persisted, the
+    /// watchdog starts counting anew after a restart.
+    #[serde(skip)]
+    pub open_since: Option<DateTime<Tz>>,
+    #[serde(skip)]
+    pub health: ValveHealth,
+    /// What the controller last reported, `None` if it couldn't be asked.
+    #[serde(skip)]
+    pub reported_status: Option<ValveStatus>,
+    /// What the valve was commanded to when `reported_status` was read.
+    #[serde(skip)]
+    pub commanded_status: Option<ValveStatus>,
 }
 
 impl Valve {
@@ -149,92 +898,2595 @@ impl Valve {
             name: name.into(),
             valve_number,
             automation_status: AutomationStatus::ForceClose,
+            previous_automation_status: None,
             schedule: Schedule::empty(),
+            schedule_kind: ScheduleKind::default(),
+            interval_schedule: IntervalSchedule::default(),
+            calendar: Calendar::default(),
+            season: None,
+            water_budget: None,
+            role: ValveRole::default(),
+            max_open_minutes: None,
+            moisture_threshold: None,
+            last_skip: None,
+            last_run: None,
+            open_since: None,
+            health: ValveHealth::default(),
+            reported_status: None,
+            commanded_status: None,
         }
     }
 
-    pub fn valve_status(&self, current_time: NaiveDateTime) -> ValveStatus {
-        match self.automation_status {
-            AutomationStatus::ForceClose => ValveStatus::Close,
-            AutomationStatus::ForceOpen => ValveStatus::Open,
-            AutomationStatus::Scheduled => {
-                let daily_schedule = &self.schedule[&current_time.weekday()];
-                match daily_schedule.should_be_running(&current_time.time()) {
-                    true => ValveStatus::Open,
-                    false => ValveStatus::Close,
+    pub fn automation_status(&self) -> &AutomationStatus {
+        &self.automation_status
+    }
+
+    /// Switches the mode. A timed override remembers the mode it replaces,
+    /// or the one the override it replaces would have returned to, and has
+    /// to end after `now`.
+    pub fn set_automation_status(
+        &mut self,
+        status: AutomationStatus,
+        now: &DateTime<Tz>,
+    ) -> Result<(), Error> {
+        match status.until() {
+            Some(until) if until <= *now => return Err(Error::InvalidOverride),
+            Some(_) => {
+                if self.automation_status.until().is_none() {
+                    self.previous_automation_status = Some(self.automation_status.clone());
                 }
             }
+            None => self.previous_automation_status = None,
         }
-    }
-    pub fn schedule(&self) -> &Schedule {
-        &self.schedule
-    }
-    pub fn add_duration(&mut self, day: &Weekday, duration: Duration) -> Result<(), Error> {
-        self.schedule[day].add_entry(duration)
+        self.automation_status = status;
+        Ok(())
     }
 
-    pub fn remove_duration(&mut self, day: &Weekday, duration: Duration) -> Result<(), Error> {
-        self.schedule[day].remove_entry(duration)
+    /// Returns to the previous mode once a timed override has ended, `true`
+    /// if it did.
+    pub fn expire_override(&mut self, time: &DateTime<Tz>) -> bool {
+        match self.automation_status.until() {
+            Some(until) if until <= *time => {
+                self.automation_status = self
+                    .previous_automation_status
+                    .take()
+                    .unwrap_or(AutomationStatus::ForceClose);
+                true
+            }
+            _ => false,
+        }
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ControllerConfig {
-    valves: Vec<Valve>,
-    pub address: Url,
-}
+    /// The mode in effect at `time`, one of `ForceOpen`, `Scheduled` and
+    /// `ForceClose`. An ended override counts as the previous mode even if
+    /// it hasn't been expired yet.
+    pub fn automation_status_at(&self, time: &DateTime<Tz>) -> AutomationStatus {
+        match &self.automation_status {
+            AutomationStatus::ForceOpenUntil(until) if *time < *until => {
+                AutomationStatus::ForceOpen
+            }
+            AutomationStatus::ForceCloseUntil(until) if *time < *until => {
+                AutomationStatus::ForceClose
+            }
+            AutomationStatus::ForceOpenUntil(_) | AutomationStatus::ForceCloseUntil(_) => self
+                .previous_automation_status
+                .clone()
+                .unwrap_or(AutomationStatus::ForceClose),
+            status => status.clone(),
+        }
+    }
 
-impl ControllerConfig {
-    pub fn new(address: Url) -> Self {
-        ControllerConfig {
-            valves: Default::default(),
-            address,
+    /// The status the valve should have at `current_time`, schedules are
+    /// evaluated against the wall clock of its timezone.
+    pub fn valve_status(&self, current_time: &DateTime<Tz>, env: &Environment) -> ValveStatus {
+        match self.automation_status_at(current_time) {
+            AutomationStatus::ForceOpen => ValveStatus::Open,
+            AutomationStatus::Scheduled
+                if env.is_suspended(current_time) || self.is_soil_wet(env) =>
+            {
+                ValveStatus::Close
+            }
+            AutomationStatus::Scheduled => match self.should_be_running(current_time, env) {
+                true => ValveStatus::Open,
+                false => ValveStatus::Close,
+            },
+            _ => ValveStatus::Close,
         }
     }
 
-    pub fn get(&self, valve_number: ValveNumber) -> Option<&Valve> {
-        self.valves.iter().find(|v| v.valve_number == valve_number)
+    /// When `valve_status` might change next without any user interaction.
+    pub fn next_change(
+        &self,
+        current_time: &DateTime<Tz>,
+        env: &Environment,
+    ) -> Option<DateTime<Tz>> {
+        if let Some(until) = self.automation_status.until() {
+            if *current_time < until {
+                return Some(until.with_timezone(&current_time.timezone()));
+            }
+        }
+        match self.automation_status_at(current_time) {
+            AutomationStatus::Scheduled if env.is_suspended(current_time) => {
+                env.suspended_until(current_time)
+            }
+            AutomationStatus::Scheduled => self.next_boundary(current_time, env),
+            _ => None,
+        }
     }
 
-    pub fn get_mut(&mut self, valve_number: ValveNumber) -> Option<&mut Valve> {
-        self.valves
-            .iter_mut()
-            .find(|v| v.valve_number == valve_number)
+    /// Whether the controller reported something else than the valve was
+    /// commanded to at the time.
+    pub fn drifted(&self) -> bool {
+        self.reported_status.is_some() && self.reported_status != self.commanded_status
     }
 
-    pub fn push(&mut self, valve: Valve) {
-        self.valves.push(valve)
+    pub fn is_zone(&self) -> bool {
+        self.role == ValveRole::Zone
     }
 
-    pub fn remove_valve(&mut self, valve_number: ValveNumber) -> bool {
-        let mut found_smt = false;
-        self.valves.retain(|v| {
-            let res = v.valve_number != valve_number;
-            if !res {
-                found_smt = true;
-            }
-            res
-        });
-        found_smt
+    pub fn water_budget(&self) -> Option<u32> {
+        self.water_budget
     }
 
-    pub fn iter(&self) -> Iter<Valve> {
-        self.valves.iter()
+    /// Sets the valve's own water budget in percent, `None` only uses the global one.
+    pub fn set_water_budget(&mut self, percent: Option<u32>) -> Result<(), Error> {
+        self.water_budget = percent.map(check_water_budget).transpose()?;
+        Ok(())
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<Valve> {
-        self.valves.iter_mut()
+    pub fn max_open_minutes(&self) -> Option<u32> {
+        self.max_open_minutes
     }
-}
 
-impl IntoIterator for ControllerConfig {
-    type Item = Valve;
+    /// Renames the valve, surrounding whitespace is dropped and the name
+    /// can't be empty.
+    pub fn set_name(&mut self, name: &str) -> Result<(), Error> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(Error::InvalidValveName);
+        }
+        self.name = name.to_owned();
+        Ok(())
+    }
 
-    type IntoIter = std::vec::IntoIter<Self::Item>;
+    /// Sets the valve's own limit on its continuous open time, `None` only
+    /// uses the global one.
+    pub fn set_max_open_minutes(&mut self, minutes: Option<u32>) -> Result<(), Error> {
+        if minutes == Some(0) {
+            return Err(Error::InvalidLimit);
+        }
+        self.max_open_minutes = minutes;
+        Ok(())
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.valves.into_iter()
+    pub fn moisture_threshold(&self) -> Option<f64> {
+        self.moisture_threshold
+    }
+
+    /// Skips scheduled runs while the soil moisture is at least `percent`,
+    /// `None` always waters.
+    pub fn set_moisture_threshold(&mut self, percent: Option<f64>) -> Result<(), Error> {
+        self.moisture_threshold = percent.map(check_moisture).transpose()?;
+        Ok(())
+    }
+
+    /// Whether the soil moisture in `env` reached the valve's threshold.
+    pub fn is_soil_wet(&self, env: &Environment) -> bool {
+        matches!(
+            (self.moisture_threshold, env.soil_moisture),
+            (Some(threshold), Some(moisture)) if moisture >= threshold
+        )
+    }
+
+    /// `env` with the valve's own water budget applied on top of the global one.
+    pub fn scaled(&self, env: &Environment) -> Environment {
+        Environment {
+            water_budget: env.water_budget * self.water_budget.unwrap_or(100) / 100,
+            ..*env
+        }
+    }
+
+    /// Whether the recurring schedule is used on `date`.
+    pub fn in_season(&self, date: &NaiveDate) -> bool {
+        self.season
+            .as_ref()
+            .map_or(true, |season| season.contains(date))
+    }
+
+    /// The entries of the recurring schedule started on `date`.
+    fn recurring_on(&self, date: &NaiveDate) -> Option<&DailySchedule> {
+        match self.schedule_kind {
+            ScheduleKind::Weekly => Some(&self.schedule[&date.weekday()]),
+            ScheduleKind::Interval if self.interval_schedule.runs_on(date) => {
+                Some(&self.interval_schedule.entries)
+            }
+            ScheduleKind::Interval => None,
+        }
+    }
+
+    /// The entries started on `date`, dated exceptions take precedence over
+    /// the recurring schedule and one-off runs are added on top.
+    pub fn entries_on(&self, date: NaiveDate) -> impl Iterator<Item = &Duration> {
+        let planned = match self.calendar.exception(&date) {
+            Some(exception) => Some(exception),
+            None if self.in_season(&date) => self.recurring_on(&date),
+            None => None,
+        };
+        planned
+            .into_iter()
+            .flatten()
+            .chain(self.calendar.one_offs(&date).into_iter().flatten())
+    }
+
+    /// When the entries started on `date` begin and end, scaled by the
+    /// valve's water budget.
+    pub fn windows_on<'a>(
+        &'a self,
+        date: NaiveDate,
+        tz: &'a Tz,
+        env: &Environment,
+    ) -> impl Iterator<Item = (DateTime<Tz>, DateTime<Tz>)> + 'a {
+        let env = self.scaled(env);
+        self.entries_on(date)
+            .filter_map(move |duration| duration.at(date, tz, &env))
+    }
+
+    /// Since when an entry started on the day of `time` or spilling over
+    /// from the day before is running at `time`; the end itself is excluded
+    /// so consecutive entries hand over exactly at the boundary.
+    pub fn running_since(&self, time: &DateTime<Tz>, env: &Environment) -> Option<DateTime<Tz>> {
+        let tz = time.timezone();
+        let today = time.naive_local().date();
+        [today.pred(), today]
+            .iter()
+            .flat_map(|date| self.windows_on(*date, &tz, env).collect::<Vec<_>>())
+            .filter(|(begin, end)| begin <= time && time < end)
+            .map(|(begin, _)| begin)
+            .min()
+    }
+
+    fn should_be_running(&self, time: &DateTime<Tz>, env: &Environment) -> bool {
+        self.running_since(time, env).is_some()
+    }
+
+    /// The windows of all entries that may still matter after `time`.
+    fn windows_ahead(
+        &self,
+        time: &DateTime<Tz>,
+        env: &Environment,
+    ) -> Vec<(DateTime<Tz>, DateTime<Tz>)> {
+        let tz = time.timezone();
+        // Starting the day before catches entries running past midnight, and
+        // another week or interval covers every recurring entry again
+        let horizon = match self.schedule_kind {
+            ScheduleKind::Weekly => 7,
+            ScheduleKind::Interval => self.interval_schedule.every_days.max(7).into(),
+        };
+        (-1..=horizon)
+            .map(|offset| time.naive_local().date() + chrono::Duration::days(offset))
+            .flat_map(|date| self.windows_on(date, &tz, env).collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// The first begin or end of any entry strictly after `time`.
+    fn next_boundary(&self, time: &DateTime<Tz>, env: &Environment) -> Option<DateTime<Tz>> {
+        self.windows_ahead(time, env)
+            .into_iter()
+            .flat_map(|(begin, end)| IntoIterator::into_iter([begin, end]))
+            .filter(|boundary| boundary > time)
+            .min()
+    }
+
+    /// The first begin of any entry strictly after `time`.
+    pub fn next_start(&self, time: &DateTime<Tz>, env: &Environment) -> Option<DateTime<Tz>> {
+        self.windows_ahead(time, env)
+            .into_iter()
+            .map(|(begin, _)| begin)
+            .filter(|begin| begin > time)
+            .min()
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    pub fn interval_schedule(&self) -> &IntervalSchedule {
+        &self.interval_schedule
+    }
+
+    pub fn interval_schedule_mut(&mut self) -> &mut IntervalSchedule {
+        &mut self.interval_schedule
+    }
+
+    pub fn calendar(&self) -> &Calendar {
+        &self.calendar
+    }
+
+    pub fn add_duration(
+        &mut self,
+        day: &Weekday,
+        duration: Duration,
+        site: &Site,
+    ) -> Result<(), Error> {
+        self.schedule.add_entry(day, duration, site)
+    }
+
+    pub fn remove_duration(&mut self, day: &Weekday, duration: Duration) -> Result<(), Error> {
+        self.schedule[day].remove_entry(duration)
+    }
+
+    /// Whether `duration` started on `date` would collide with the entries
+    /// of the surrounding days.
+    fn overlaps_neighbours(&self, date: NaiveDate, duration: &Duration, site: &Site) -> bool {
+        overlapping(self.entries_on(date.pred()), duration, 1, site)
+            || overlapping(self.entries_on(date.succ()), duration, -1, site)
+    }
+
+    /// Skips all weekly entries on `date`, dropping any replacements for it.
+    pub fn skip_date(&mut self, date: NaiveDate) {
+        self.calendar
+            .exceptions
+            .insert(date, DailySchedule::default());
+    }
+
+    /// Runs `duration` on `date` instead of the weekly entries.
+    pub fn add_exception(
+        &mut self,
+        date: NaiveDate,
+        duration: Duration,
+        site: &Site,
+    ) -> Result<(), Error> {
+        let one_offs = self.calendar.one_offs(&date).into_iter().flatten();
+        if overlapping(one_offs, &duration, 0, site)
+            || self.overlaps_neighbours(date, &duration, site)
+        {
+            return Err(Error::OverlappingDurations);
+        }
+        self.calendar
+            .exceptions
+            .entry(date)
+            .or_default()
+            .add_entry(duration, site)
+    }
+
+    /// Runs `duration` on `date` in addition to the planned entries.
+    pub fn add_one_off(
+        &mut self,
+        date: NaiveDate,
+        duration: Duration,
+        site: &Site,
+    ) -> Result<(), Error> {
+        if overlapping(self.entries_on(date), &duration, 0, site)
+            || self.overlaps_neighbours(date, &duration, site)
+        {
+            return Err(Error::OverlappingDurations);
+        }
+        self.calendar
+            .one_offs
+            .entry(date)
+            .or_default()
+            .add_entry(duration, site)
+    }
+
+    /// Goes back to the weekly entries on `date`.
+    pub fn remove_exception(&mut self, date: &NaiveDate) -> Result<(), Error> {
+        self.calendar
+            .exceptions
+            .remove(date)
+            .map(|_| ())
+            .ok_or(Error::MissingDuration)
+    }
+
+    /// Removing the last duration of an exception goes back to the weekly
+    /// entries, use `skip_date` to not water at all.
+    pub fn remove_exception_duration(
+        &mut self,
+        date: &NaiveDate,
+        duration: Duration,
+    ) -> Result<(), Error> {
+        let exception = self
+            .calendar
+            .exceptions
+            .get_mut(date)
+            .ok_or(Error::MissingDuration)?;
+        exception.remove_entry(duration)?;
+        if exception.is_empty() {
+            self.calendar.exceptions.remove(date);
+        }
+        Ok(())
+    }
+
+    pub fn remove_one_off(&mut self, date: &NaiveDate, duration: Duration) -> Result<(), Error> {
+        let one_offs = self
+            .calendar
+            .one_offs
+            .get_mut(date)
+            .ok_or(Error::MissingDuration)?;
+        one_offs.remove_entry(duration)?;
+        if one_offs.is_empty() {
+            self.calendar.one_offs.remove(date);
+        }
+        Ok(())
+    }
+}
+
+/// Readings older than this are ignored, the sensor is probably broken.
+const SENSOR_TIMEOUT_HOURS: i64 = 6;
+
+/// The longest rain delay, 30 days.
+const MAX_RAIN_DELAY_HOURS: u32 = 30 * 24;
+
+/// How many safety events are kept, acknowledged ones are dropped first.
+const MAX_SAFETY_EVENTS: usize = 100;
+
+fn check_moisture(percent: f64) -> Result<f64, Error> {
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(Error::InvalidMoisture);
+    }
+    Ok(percent)
+}
+
+pub type SensorId = String;
+
+/// A soil moisture sensor in the bed of one or more valves.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Sensor {
+    pub id: SensorId,
+    pub name: String,
+    valves: Vec<ValveNumber>,
+    /// Moisture in percent.
+    moisture: Option<f64>,
+    read_at: Option<DateTime<Utc>>,
+}
+
+impl Sensor {
+    pub fn new(id: impl Into<SensorId>, name: impl Into<String>) -> Self {
+        Sensor {
+            id: id.into(),
+            name: name.into(),
+            valves: Vec::new(),
+            moisture: None,
+            read_at: None,
+        }
+    }
+
+    pub fn valves(&self) -> &[ValveNumber] {
+        &self.valves
+    }
+
+    pub fn moisture(&self) -> Option<f64> {
+        self.moisture
+    }
+
+    pub fn read_at(&self) -> Option<DateTime<Utc>> {
+        self.read_at
+    }
+
+    pub fn record(&mut self, moisture: f64, time: &DateTime<Tz>) -> Result<(), Error> {
+        self.moisture = Some(check_moisture(moisture)?);
+        self.read_at = Some(time.with_timezone(&Utc));
+        Ok(())
+    }
+
+    /// Whether the last reading is recent enough to be trusted at `time`.
+    pub fn is_fresh(&self, time: &DateTime<Tz>) -> bool {
+        self.read_at
+            .is_some_and(|read_at| *time < read_at + chrono::Duration::hours(SENSOR_TIMEOUT_HOURS))
+    }
+}
+
+/// Why a scheduled run didn't happen.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    RainDelay,
+    Weather,
+    SoilWet { sensor: SensorId, moisture: f64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SkippedRun {
+    /// When the run would have begun.
+    pub begin: DateTime<Utc>,
+    pub reason: SkipReason,
+}
+
+pub type ProgramId = u32;
+
+/// One valve run of a `Program`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProgramStep {
+    pub valve_number: ValveNumber,
+    /// Scaled by the water budget like the run lengths of schedule entries.
+    pub run_minutes: u32,
+}
+
+/// A single step of a program as it runs on a given day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramRun {
+    pub valve_number: ValveNumber,
+    pub begin: DateTime<Tz>,
+    pub end: DateTime<Tz>,
+}
+
+/// Runs several valves one after another on the given weekdays, so a
+/// water supply too weak for all zones at once is never overdrawn.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Program {
+    pub id: ProgramId,
+    pub name: String,
+    pub start: TimeOfDay,
+    weekdays: Vec<Weekday>,
+    /// How long to wait between two steps so the water can soak in.
+    pub pause_minutes: u32,
+    steps: Vec<ProgramStep>,
+}
+
+impl Program {
+    pub fn new(name: impl Into<String>, start: TimeOfDay) -> Self {
+        Program {
+            id: 0,
+            name: name.into(),
+            start,
+            weekdays: Vec::new(),
+            pause_minutes: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn weekdays(&self) -> &[Weekday] {
+        &self.weekdays
+    }
+
+    pub fn set_weekdays(&mut self, weekdays: impl IntoIterator<Item = Weekday>) {
+        let mut weekdays: Vec<_> = weekdays.into_iter().collect();
+        weekdays.sort_by_key(|day| day.num_days_from_monday());
+        weekdays.dedup();
+        self.weekdays = weekdays;
+    }
+
+    pub fn steps(&self) -> &[ProgramStep] {
+        &self.steps
+    }
+
+    pub fn add_step(&mut self, step: ProgramStep) -> Result<(), Error> {
+        if step.run_minutes == 0 {
+            return Err(Error::EmptyDuration);
+        }
+        self.steps.push(step);
+        Ok(())
+    }
+
+    pub fn remove_step(&mut self, index: usize) -> Result<(), Error> {
+        if index >= self.steps.len() {
+            return Err(Error::MissingProgramStep);
+        }
+        self.steps.remove(index);
+        Ok(())
+    }
+
+    /// Drops all steps of a valve that no longer exists.
+    fn remove_valve(&mut self, valve_number: ValveNumber) {
+        self.steps.retain(|step| step.valve_number != valve_number);
+    }
+
+    /// The steps of the program started on `date`, one after another with
+    /// the pause in between. `run_length` decides how long each step lasts.
+    pub fn runs_on(
+        &self,
+        date: NaiveDate,
+        tz: &Tz,
+        location: Option<&Location>,
+        run_length: impl Fn(&ProgramStep) -> chrono::Duration,
+    ) -> Vec<ProgramRun> {
+        if !self.weekdays.contains(&date.weekday()) {
+            return Vec::new();
+        }
+        let mut begin = match self.start.on(date, tz, location) {
+            Some(start) => resolve_local(tz, &start),
+            None => return Vec::new(),
+        };
+        let pause = chrono::Duration::minutes(self.pause_minutes.into());
+        self.steps
+            .iter()
+            .map(|step| {
+                let end = begin + run_length(step);
+                let run = ProgramRun {
+                    valve_number: step.valve_number,
+                    begin,
+                    end,
+                };
+                begin = end + pause;
+                run
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControllerConfig {
+    #[serde(default)]
+    pub name: String,
+    valves: Vec<Valve>,
+    pub address: Url,
+    /// How the valves are switched.
+    #[serde(default)]
+    transport: TransportConfig,
+    /// The timezone the schedules are written in.
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    /// Required for entries relative to sunrise or sunset.
+    #[serde(default)]
+    pub location: Option<Location>,
+    /// Percentage all run lengths are scaled with.
+    #[serde(
+        default = "default_water_budget",
+        deserialize_with = "water_budget_from"
+    )]
+    water_budget: u32,
+    #[serde(default)]
+    programs: Vec<Program>,
+    /// How many valves may be open at the same time, `None` for no limit.
+    #[serde(default)]
+    max_concurrent_open: Option<usize>,
+    /// How long any valve may stay open in one go, `None` for no limit.
+    #[serde(default)]
+    max_open_minutes: Option<u32>,
+    #[serde(default)]
+    safety_events: Vec<SafetyEvent>,
+    /// Scheduled watering is suspended until then.
+    #[serde(default)]
+    rain_delay_until: Option<DateTime<Utc>>,
+    /// Rainfall from which on the weather skips the day, `None` to never skip.
+    #[serde(default)]
+    rain_skip_mm: Option<f64>,
+    #[serde(default)]
+    weather: Option<WeatherDecision>,
+    #[serde(default)]
+    sensors: Vec<Sensor>,
+}
+
+/// A valve the watchdog closed because it was open for too long.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SafetyEvent {
+    pub valve_number: ValveNumber,
+    pub time: DateTime<Utc>,
+    pub open_minutes: i64,
+    #[serde(default)]
+    pub acknowledged: bool,
+}
+
+/// The statuses the valves should have at a point in time.
+#[derive(Debug, Default)]
+pub struct Plan {
+    statuses: HashMap<ValveNumber, ValveStatus>,
+    /// Valves that should be open but wait for a free slot, next in line first.
+    pub queue: Vec<ValveNumber>,
+    /// Where runs pushed back by `max_concurrent_open` begin or end after
+    /// the time of the plan.
+    deferred_changes: Vec<DateTime<Tz>>,
+}
+
+impl Plan {
+    pub fn status(&self, valve_number: ValveNumber) -> ValveStatus {
+        self.statuses
+            .get(&valve_number)
+            .cloned()
+            .unwrap_or(ValveStatus::Close)
+    }
+
+    pub fn statuses(&self) -> &HashMap<ValveNumber, ValveStatus> {
+        &self.statuses
+    }
+
+    pub fn is_queued(&self, valve_number: ValveNumber) -> bool {
+        self.queue.contains(&valve_number)
+    }
+
+    fn any_open(&self) -> bool {
+        self.statuses
+            .values()
+            .any(|status| *status == ValveStatus::Open)
+    }
+}
+
+/// When `runs` actually run if only `slots` of them may run at once. A run
+/// finding no free slot waits for one in the order the runs began and then
+/// runs for its full length.
+fn deferred_runs(
+    runs: Vec<(DateTime<Tz>, DateTime<Tz>, ValveNumber)>,
+    slots: usize,
+) -> Vec<(DateTime<Tz>, DateTime<Tz>, ValveNumber)> {
+    let slots = slots.max(1);
+    let mut running: Vec<DateTime<Tz>> = Vec::new();
+    let mut previous_start = None;
+    runs.into_iter()
+        .map(|(begin, end, valve_number)| {
+            // Nobody overtakes a run waiting in line
+            let mut start = previous_start.map_or(begin, |previous| begin.max(previous));
+            running.retain(|running_end| *running_end > start);
+            if running.len() >= slots {
+                running.sort();
+                start = running[running.len() - slots];
+                running.retain(|running_end| *running_end > start);
+            }
+            previous_start = Some(start);
+            let deferred_end = start + (end - begin);
+            running.push(deferred_end);
+            (start, deferred_end, valve_number)
+        })
+        .collect()
+}
+
+/// A moment at which more scheduled valves would be open than allowed.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConcurrencyConflict {
+    pub time: DateTime<Tz>,
+    pub valves: Vec<ValveNumber>,
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+fn default_water_budget() -> u32 {
+    100
+}
+
+impl ControllerConfig {
+    pub fn new(address: Url) -> Self {
+        ControllerConfig {
+            name: String::new(),
+            valves: Default::default(),
+            address,
+            transport: TransportConfig::default(),
+            timezone: default_timezone(),
+            location: None,
+            water_budget: default_water_budget(),
+            programs: Vec::new(),
+            max_concurrent_open: None,
+            max_open_minutes: None,
+            safety_events: Vec::new(),
+            rain_delay_until: None,
+            rain_skip_mm: None,
+            weather: None,
+            sensors: Vec::new(),
+        }
+    }
+
+    pub fn water_budget(&self) -> u32 {
+        self.water_budget
+    }
+
+    pub fn transport(&self) -> &TransportConfig {
+        &self.transport
+    }
+
+    /// MQTT needs a broker and a topic prefix.
+    pub fn set_transport(&mut self, transport: TransportConfig) -> Result<(), Error> {
+        if let TransportConfig::Mqtt {
+            host, topic_prefix, ..
+        } = &transport
+        {
+            if host.trim().is_empty() || topic_prefix.trim_matches('/').trim().is_empty() {
+                return Err(Error::InvalidTransport);
+            }
+        }
+        self.transport = transport;
+        Ok(())
+    }
+
+    pub fn set_water_budget(&mut self, percent: u32) -> Result<(), Error> {
+        self.water_budget = check_water_budget(percent)?;
+        Ok(())
+    }
+
+    pub fn environment(&self) -> Environment {
+        Environment {
+            location: self.location,
+            water_budget: self.water_budget,
+            rain_delay_until: self.rain_delay_until,
+            weather: self.weather.as_ref().map(|decision| decision.adjustment),
+            soil_moisture: None,
+        }
+    }
+
+    /// Where new entries are checked for overlaps.
+    pub fn site(&self) -> Site {
+        Site {
+            timezone: self.timezone,
+            location: self.location,
+        }
+    }
+
+    /// The environment with the soil moisture at `valve` at `time`.
+    pub fn environment_for(&self, valve: &Valve, time: &DateTime<Tz>) -> Environment {
+        Environment {
+            soil_moisture: self
+                .wettest_sensor(valve.valve_number, time)
+                .and_then(|sensor| sensor.moisture),
+            ..self.environment()
+        }
+    }
+
+    pub fn weather(&self) -> Option<&WeatherDecision> {
+        self.weather.as_ref()
+    }
+
+    pub fn set_weather(&mut self, decision: WeatherDecision) {
+        self.weather = Some(decision);
+    }
+
+    pub fn rain_skip_mm(&self) -> Option<f64> {
+        self.rain_skip_mm
+    }
+
+    /// Lets the weather skip days with at least `rain_mm` of rain, `None`
+    /// only adjusts the run lengths.
+    pub fn set_rain_skip_mm(&mut self, rain_mm: Option<f64>) -> Result<(), Error> {
+        if rain_mm.is_some_and(|rain_mm| rain_mm.is_nan() || rain_mm < 0.0) {
+            return Err(Error::InvalidRainThreshold);
+        }
+        self.rain_skip_mm = rain_mm;
+        // The last report is judged again instead of waiting for the next one
+        if let Some(decision) = &mut self.weather {
+            decision.adjustment =
+                WeatherAdjustment::from_report(&decision.report, decision.adjustment.date, rain_mm);
+        }
+        Ok(())
+    }
+
+    /// The end of the rain delay if one is running at `time`.
+    pub fn rain_delay_until(&self, time: &DateTime<Tz>) -> Option<DateTime<Utc>> {
+        self.rain_delay_until.filter(|until| *time < *until)
+    }
+
+    /// Suspends scheduled watering for `hours` from `now` on, forced valves
+    /// are left alone. Up to `MAX_RAIN_DELAY_HOURS` can be given.
+    pub fn delay_for_rain(&mut self, hours: u32, now: &DateTime<Tz>) -> Result<(), Error> {
+        if hours == 0 || hours > MAX_RAIN_DELAY_HOURS {
+            return Err(Error::InvalidRainDelay);
+        }
+        self.rain_delay_until =
+            Some(now.with_timezone(&Utc) + chrono::Duration::hours(hours.into()));
+        Ok(())
+    }
+
+    pub fn cancel_rain_delay(&mut self) {
+        self.rain_delay_until = None;
+    }
+
+    /// The current time in the timezone of the schedules.
+    pub fn now(&self) -> DateTime<Tz> {
+        Utc::now().with_timezone(&self.timezone)
+    }
+
+    pub fn get(&self, valve_number: ValveNumber) -> Option<&Valve> {
+        self.valves.iter().find(|v| v.valve_number == valve_number)
+    }
+
+    pub fn get_mut(&mut self, valve_number: ValveNumber) -> Option<&mut Valve> {
+        self.valves
+            .iter_mut()
+            .find(|v| v.valve_number == valve_number)
+    }
+
+    pub fn push(&mut self, valve: Valve) {
+        self.valves.push(valve)
+    }
+
+    pub fn remove_valve(&mut self, valve_number: ValveNumber) -> bool {
+        let mut found_smt = false;
+        self.valves.retain(|v| {
+            let res = v.valve_number != valve_number;
+            if !res {
+                found_smt = true;
+            }
+            res
+        });
+        for program in &mut self.programs {
+            program.remove_valve(valve_number);
+        }
+        for sensor in &mut self.sensors {
+            sensor.valves.retain(|v| *v != valve_number);
+        }
+        found_smt
+    }
+
+    #[allow(unknown_lints, mismatched_lifetime_syntaxes)]
+    pub fn iter(&self) -> Iter<Valve> {
+        self.valves.iter()
+    }
+
+    #[allow(unknown_lints, mismatched_lifetime_syntaxes)]
+    pub fn iter_mut(&mut self) -> IterMut<Valve> {
+        self.valves.iter_mut()
+    }
+
+    /// Takes over what isn't persisted from `previous`, for a config that was
+    /// read back from its saved state.
+    pub fn keep_runtime_state(&mut self, previous: &ControllerConfig) {
+        for valve in self.valves.iter_mut() {
+            if let Some(old) = previous.get(valve.valve_number) {
+                valve.open_since = old.open_since;
+                valve.health = old.health.clone();
+                valve.reported_status = old.reported_status.clone();
+                valve.commanded_status = old.commanded_status.clone();
+            }
+        }
+    }
+
+    pub fn programs(&self) -> Iter<'_, Program> {
+        self.programs.iter()
+    }
+
+    pub fn program(&self, id: ProgramId) -> Option<&Program> {
+        self.programs.iter().find(|p| p.id == id)
+    }
+
+    pub fn program_mut(&mut self, id: ProgramId) -> Option<&mut Program> {
+        self.programs.iter_mut().find(|p| p.id == id)
+    }
+
+    /// Adds `program` under a new id, which is returned.
+    pub fn push_program(&mut self, mut program: Program) -> ProgramId {
+        program.id = self.programs.iter().map(|p| p.id + 1).max().unwrap_or(0);
+        let id = program.id;
+        self.programs.push(program);
+        id
+    }
+
+    pub fn remove_program(&mut self, id: ProgramId) -> Result<(), Error> {
+        let count = self.programs.len();
+        self.programs.retain(|p| p.id != id);
+        if self.programs.len() == count {
+            return Err(Error::InvalidProgramId);
+        }
+        Ok(())
+    }
+
+    /// Changes what a valve supplies. Only zones can be program steps, so a
+    /// valve a program runs has to stay a zone until it is taken out.
+    pub fn set_role(&mut self, valve_number: ValveNumber, role: ValveRole) -> Result<(), Error> {
+        let in_program = self.programs.iter().any(|program| {
+            program
+                .steps()
+                .iter()
+                .any(|step| step.valve_number == valve_number)
+        });
+        let valve = self
+            .get_mut(valve_number)
+            .ok_or(Error::InvalidValveNumber)?;
+        if role != ValveRole::Zone && in_program {
+            return Err(Error::InvalidValveRole);
+        }
+        valve.role = role;
+        Ok(())
+    }
+
+    /// Adds a step to a program, the valve has to exist and be a zone.
+    pub fn add_program_step(&mut self, id: ProgramId, step: ProgramStep) -> Result<(), Error> {
+        if !self
+            .get(step.valve_number)
+            .ok_or(Error::InvalidValveNumber)?
+            .is_zone()
+        {
+            return Err(Error::InvalidValveRole);
+        }
+        self.program_mut(id)
+            .ok_or(Error::InvalidProgramId)?
+            .add_step(step)
+    }
+
+    /// The steps of `program` started on `date`, each scaled by the water
+    /// budget of its valve.
+    pub fn program_runs(&self, program: &Program, date: NaiveDate) -> Vec<ProgramRun> {
+        let env = self.environment();
+        program.runs_on(date, &self.timezone, env.location.as_ref(), |step| {
+            self.get(step.valve_number)
+                .map(|valve| valve.scaled(&env))
+                .unwrap_or(env)
+                .run_length(date, step.run_minutes)
+        })
+    }
+
+    /// The steps of all programs started on `date`.
+    fn program_runs_on(&self, date: NaiveDate) -> impl Iterator<Item = ProgramRun> + '_ {
+        self.programs
+            .iter()
+            .flat_map(move |program| self.program_runs(program, date))
+    }
+
+    /// Since when a program started on the day of `time` or the day before
+    /// runs `valve_number` at `time`.
+    fn program_running_since(
+        &self,
+        valve_number: ValveNumber,
+        time: &DateTime<Tz>,
+    ) -> Option<DateTime<Tz>> {
+        let today = time.naive_local().date();
+        [today.pred(), today]
+            .iter()
+            .flat_map(|date| self.program_runs_on(*date))
+            .filter(|run| run.valve_number == valve_number && run.begin <= *time && *time < run.end)
+            .map(|run| run.begin)
+            .min()
+    }
+
+    /// The status `valve` should have at `time` without regard to
+    /// `max_concurrent_open`, scheduled valves are also opened while a
+    /// program runs them.
+    pub fn valve_status(&self, valve: &Valve, time: &DateTime<Tz>) -> ValveStatus {
+        let env = self.environment_for(valve, time);
+        match valve.valve_status(time, &env) {
+            ValveStatus::Close
+                if valve.automation_status_at(time) == AutomationStatus::Scheduled
+                    && !env.is_suspended(time)
+                    && !valve.is_soil_wet(&env)
+                    && self
+                        .program_running_since(valve.valve_number, time)
+                        .is_some() =>
+            {
+                ValveStatus::Open
+            }
+            status => status,
+        }
+    }
+
+    /// When `valve` is started next by its schedule or a program, whether
+    /// or not it is in automatic mode or watering is suspended.
+    pub fn next_run(&self, valve: &Valve, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let programs = (-1..=7)
+            .map(|offset| time.naive_local().date() + chrono::Duration::days(offset))
+            .flat_map(|date| self.program_runs_on(date))
+            .filter(|run| run.valve_number == valve.valve_number)
+            .map(|run| run.begin);
+        valve
+            .next_start(time, &self.environment())
+            .into_iter()
+            .chain(programs)
+            .filter(|begin| begin > time)
+            .min()
+    }
+
+    /// Why the scheduled run of `valve` going on at `time` is skipped, if
+    /// it is.
+    pub fn skip_reason(&self, valve: &Valve, time: &DateTime<Tz>) -> Option<SkipReason> {
+        if valve.automation_status_at(time) != AutomationStatus::Scheduled {
+            return None;
+        }
+        let env = self.environment_for(valve, time);
+        if env.rain_delay_until.is_some_and(|until| *time < until) {
+            Some(SkipReason::RainDelay)
+        } else if env.is_suspended(time) {
+            Some(SkipReason::Weather)
+        } else if valve.is_soil_wet(&env) {
+            let sensor = self.wettest_sensor(valve.valve_number, time)?;
+            Some(SkipReason::SoilWet {
+                sensor: sensor.id.clone(),
+                moisture: sensor.moisture?,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Since when the scheduled run of `valve` going on at `time` would have
+    /// been running, ignoring anything that skips it.
+    fn scheduled_since(&self, valve: &Valve, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        IntoIterator::into_iter([
+            valve.running_since(time, &self.environment()),
+            self.program_running_since(valve.valve_number, time),
+        ])
+        .flatten()
+        .min()
+    }
+
+    /// Remembers the runs skipped at `time` in `Valve::last_skip`, returns
+    /// the valves that skipped a run they hadn't skipped before.
+    pub fn record_skips(&mut self, time: &DateTime<Tz>) -> Vec<ValveNumber> {
+        let skips: Vec<_> = self
+            .iter()
+            .filter_map(|valve| {
+                let begin = self.scheduled_since(valve, time)?;
+                let reason = self.skip_reason(valve, time)?;
+                let skip = SkippedRun {
+                    begin: begin.with_timezone(&Utc),
+                    reason,
+                };
+                let known = valve
+                    .last_skip
+                    .as_ref()
+                    .is_some_and(|last| last.begin == skip.begin);
+                (!known).then_some((valve.valve_number, skip))
+            })
+            .collect();
+        skips
+            .into_iter()
+            .map(|(valve_number, skip)| {
+                if let Some(valve) = self.get_mut(valve_number) {
+                    valve.last_skip = Some(skip);
+                }
+                valve_number
+            })
+            .collect()
+    }
+
+    /// Remembers in `Valve::last_run` when the valves opening according to
+    /// `plan` started, returns those valves. Has to come before `watch`,
+    /// which takes note of the open valves.
+    pub fn record_runs(&mut self, plan: &Plan, time: &DateTime<Tz>) -> Vec<ValveNumber> {
+        self.valves
+            .iter_mut()
+            .filter(|valve| {
+                valve.open_since.is_none() && plan.status(valve.valve_number) == ValveStatus::Open
+            })
+            .map(|valve| {
+                valve.last_run = Some(time.with_timezone(&Utc));
+                valve.valve_number
+            })
+            .collect()
+    }
+
+    pub fn sensors(&self) -> &[Sensor] {
+        &self.sensors
+    }
+
+    pub fn sensor_mut(&mut self, id: &str) -> Option<&mut Sensor> {
+        self.sensors.iter_mut().find(|sensor| sensor.id == id)
+    }
+
+    pub fn add_sensor(&mut self, sensor: Sensor) -> Result<(), Error> {
+        if sensor.id.is_empty() || self.sensors.iter().any(|other| other.id == sensor.id) {
+            return Err(Error::InvalidSensorId);
+        }
+        self.sensors.push(sensor);
+        Ok(())
+    }
+
+    pub fn remove_sensor(&mut self, id: &str) -> Result<(), Error> {
+        let count = self.sensors.len();
+        self.sensors.retain(|sensor| sensor.id != id);
+        if self.sensors.len() == count {
+            return Err(Error::InvalidSensorId);
+        }
+        Ok(())
+    }
+
+    /// Places the sensor `id` in the beds of `valves`.
+    pub fn set_sensor_valves(&mut self, id: &str, valves: Vec<ValveNumber>) -> Result<(), Error> {
+        if valves
+            .iter()
+            .any(|valve_number| self.get(*valve_number).is_none())
+        {
+            return Err(Error::InvalidValveNumber);
+        }
+        let sensor = self.sensor_mut(id).ok_or(Error::InvalidSensorId)?;
+        sensor.valves = valves;
+        sensor.valves.sort_unstable();
+        sensor.valves.dedup();
+        Ok(())
+    }
+
+    /// The sensor at `valve_number` with the highest reading recent enough
+    /// to count at `time`.
+    fn wettest_sensor(&self, valve_number: ValveNumber, time: &DateTime<Tz>) -> Option<&Sensor> {
+        self.sensors
+            .iter()
+            .filter(|sensor| sensor.valves.contains(&valve_number) && sensor.is_fresh(time))
+            .max_by(|a, b| {
+                a.moisture
+                    .partial_cmp(&b.moisture)
+                    .unwrap_or(Ordering::Equal)
+            })
+    }
+
+    /// Ends the timed overrides that ran out by `time`, `true` if any did.
+    pub fn expire_overrides(&mut self, time: &DateTime<Tz>) -> bool {
+        self.iter_mut().fold(false, |expired, valve| {
+            valve.expire_override(time) | expired
+        })
+    }
+
+    pub fn max_concurrent_open(&self) -> Option<usize> {
+        self.max_concurrent_open
+    }
+
+    /// Limits how many valves may be open at once, `None` for no limit.
+    pub fn set_max_concurrent_open(&mut self, limit: Option<usize>) -> Result<(), Error> {
+        if limit == Some(0) {
+            return Err(Error::InvalidLimit);
+        }
+        self.max_concurrent_open = limit;
+        Ok(())
+    }
+
+    pub fn max_open_minutes(&self) -> Option<u32> {
+        self.max_open_minutes
+    }
+
+    /// Limits how long any valve may stay open in one go, `None` for no limit.
+    pub fn set_max_open_minutes(&mut self, minutes: Option<u32>) -> Result<(), Error> {
+        if minutes == Some(0) {
+            return Err(Error::InvalidLimit);
+        }
+        self.max_open_minutes = minutes;
+        Ok(())
+    }
+
+    /// How long `valve` may stay open in one go, its own limit wins over
+    /// the global one. Master valves and pumps in automatic mode stay open as
+    /// long as any zone runs, the global limit only applies to them when
+    /// they are forced open.
+    pub fn max_open_duration(&self, valve: &Valve) -> Option<chrono::Duration> {
+        let forced_open = matches!(
+            valve.automation_status(),
+            AutomationStatus::ForceOpen | AutomationStatus::ForceOpenUntil(_)
+        );
+        valve
+            .max_open_minutes
+            .or_else(|| {
+                self.max_open_minutes
+                    .filter(|_| valve.is_zone() || forced_open)
+            })
+            .map(|minutes| chrono::Duration::minutes(minutes.into()))
+    }
+
+    pub fn safety_events(&self) -> &[SafetyEvent] {
+        &self.safety_events
+    }
+
+    pub fn acknowledge_safety_events(&mut self) {
+        for event in &mut self.safety_events {
+            event.acknowledged = true;
+        }
+        self.prune_safety_events();
+    }
+
+    /// Drops the oldest acknowledged safety events beyond `MAX_SAFETY_EVENTS`.
+    fn prune_safety_events(&mut self) {
+        let mut excess = self.safety_events.len().saturating_sub(MAX_SAFETY_EVENTS);
+        self.safety_events.retain(|event| {
+            if excess > 0 && event.acknowledged {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Tracks since when the valves are open according to `plan` and forces
+    /// those open for longer than allowed closed, whatever their mode.
+    /// Returns the safety events for the valves it closed.
+    pub fn watch(&mut self, plan: &Plan, time: &DateTime<Tz>) -> Vec<SafetyEvent> {
+        let limits: HashMap<_, _> = self
+            .iter()
+            .map(|valve| (valve.valve_number, self.max_open_duration(valve)))
+            .collect();
+        let mut events = Vec::new();
+        for valve in self.valves.iter_mut() {
+            if plan.status(valve.valve_number) == ValveStatus::Close {
+                valve.open_since = None;
+                continue;
+            }
+            let since = *valve.open_since.get_or_insert(*time);
+            match limits[&valve.valve_number] {
+                Some(limit) if since + limit <= *time => {
+                    valve.automation_status = AutomationStatus::ForceClose;
+                    valve.previous_automation_status = None;
+                    valve.open_since = None;
+                    events.push(SafetyEvent {
+                        valve_number: valve.valve_number,
+                        time: time.with_timezone(&Utc),
+                        open_minutes: (*time - since).num_minutes(),
+                        acknowledged: false,
+                    });
+                }
+                _ => {}
+            }
+        }
+        self.safety_events.extend(events.iter().cloned());
+        self.prune_safety_events();
+        events
+    }
+
+    /// When the next open valve runs into its limit.
+    pub fn watchdog_deadline(&self) -> Option<DateTime<Tz>> {
+        self.iter()
+            .filter_map(|valve| Some(valve.open_since? + self.max_open_duration(valve)?))
+            .min()
+    }
+
+    /// The statuses of all valves at `time` with `max_concurrent_open`
+    /// applied. Forced valves get a slot first, scheduled ones in the order
+    /// their entries began, so later-starting valves wait for a free slot
+    /// and then run for the full length of their entry. Master valves and
+    /// pumps follow the zones, see `ValveRole`.
+    pub fn plan(&self, time: &DateTime<Tz>) -> Plan {
+        let mut plan = self.zone_plan(time);
+        let zones_open = plan.any_open();
+        let zones_opening = || self.zone_plan(&(*time + master_lead())).any_open();
+        let zones_closing = || self.zone_plan(&(*time - master_lead())).any_open();
+        for valve in self.iter().filter(|valve| !valve.is_zone()) {
+            let status = match valve.automation_status_at(time) {
+                AutomationStatus::ForceOpen => ValveStatus::Open,
+                AutomationStatus::Scheduled => match valve.role {
+                    _ if zones_open => ValveStatus::Open,
+                    ValveRole::Master if zones_opening() || zones_closing() => ValveStatus::Open,
+                    _ => ValveStatus::Close,
+                },
+                _ => ValveStatus::Close,
+            };
+            plan.statuses.insert(valve.valve_number, status);
+        }
+        plan
+    }
+
+    /// The plan for the zones alone.
+    fn zone_plan(&self, time: &DateTime<Tz>) -> Plan {
+        let mut statuses = HashMap::new();
+        let mut forced = Vec::new();
+        for valve in self.iter().filter(|valve| valve.is_zone()) {
+            statuses.insert(valve.valve_number, ValveStatus::Close);
+            // Scheduled runs are planned below
+            if valve.automation_status_at(time) != AutomationStatus::Scheduled
+                && self.valve_status(valve, time) == ValveStatus::Open
+            {
+                forced.push(valve.valve_number);
+            }
+        }
+        // Forced valves get a slot first
+        let limit = self.max_concurrent_open.unwrap_or(usize::MAX);
+        let mut queue = forced.split_off(limit.min(forced.len()));
+        for valve_number in &forced {
+            statuses.insert(*valve_number, ValveStatus::Open);
+        }
+        // The forced valves only push aside the runs going on right now,
+        // whenever they were forced
+        let mut free = limit - forced.len();
+        let mut deferred_changes = Vec::new();
+        for (start, end, valve_number) in deferred_runs(self.due_runs(time), limit) {
+            if *time < start {
+                queue.push(valve_number);
+                deferred_changes.extend([start, end].iter());
+            } else if *time < end {
+                if free > 0 {
+                    statuses.insert(valve_number, ValveStatus::Open);
+                    free -= 1;
+                } else {
+                    queue.push(valve_number);
+                }
+                deferred_changes.push(end);
+            }
+        }
+        queue.retain(|valve_number| statuses[valve_number] == ValveStatus::Close);
+        let mut seen = HashSet::new();
+        queue.retain(|valve_number| seen.insert(*valve_number));
+        deferred_changes.retain(|change| change > time);
+        Plan {
+            statuses,
+            queue,
+            deferred_changes,
+        }
+    }
+
+    /// The runs of the scheduled zones begun by `time` that aren't skipped
+    /// at `time`, by begin. Overlapping runs of a valve, say of its schedule
+    /// and a program, are merged into one.
+    fn due_runs(&self, time: &DateTime<Tz>) -> Vec<(DateTime<Tz>, DateTime<Tz>, ValveNumber)> {
+        let env = self.environment();
+        let tz = time.timezone();
+        let today = time.naive_local().date();
+        let mut runs = Vec::new();
+        for valve in self.iter().filter(|valve| valve.is_zone()) {
+            if valve.automation_status_at(time) != AutomationStatus::Scheduled
+                || self.skip_reason(valve, time).is_some()
+            {
+                continue;
+            }
+            let mut windows: Vec<_> = [today.pred(), today]
+                .iter()
+                .flat_map(|date| {
+                    let programs = self
+                        .program_runs_on(*date)
+                        .filter(|run| run.valve_number == valve.valve_number)
+                        .map(|run| (run.begin, run.end));
+                    valve
+                        .windows_on(*date, &tz, &env)
+                        .chain(programs)
+                        .collect::<Vec<_>>()
+                })
+                .filter(|(begin, _)| begin <= time)
+                .collect();
+            windows.sort();
+            let mut merged: Vec<(DateTime<Tz>, DateTime<Tz>)> = Vec::new();
+            for (begin, end) in windows {
+                match merged.last_mut() {
+                    Some(last) if begin <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((begin, end)),
+                }
+            }
+            runs.extend(
+                merged
+                    .into_iter()
+                    .map(|(begin, end)| (begin, end, valve.valve_number)),
+            );
+        }
+        runs.sort();
+        runs
+    }
+
+    /// The moments in the week after `time` at which the entries and
+    /// programs of the scheduled valves would exceed `max_concurrent_open`.
+    /// Run lengths are taken at the current water budget.
+    pub fn concurrency_conflicts(&self, time: &DateTime<Tz>) -> Vec<ConcurrencyConflict> {
+        let limit = match self.max_concurrent_open {
+            Some(limit) => limit,
+            None => return Vec::new(),
+        };
+        let env = self.environment();
+        let tz = time.timezone();
+        let horizon = *time + chrono::Duration::days(7);
+        let dates: Vec<_> = (-1..=7)
+            .map(|offset| time.naive_local().date() + chrono::Duration::days(offset))
+            .collect();
+        let is_scheduled = |valve_number: ValveNumber| {
+            self.get(valve_number).is_some_and(|valve| {
+                valve.is_zone() && valve.automation_status_at(time) == AutomationStatus::Scheduled
+            })
+        };
+
+        let mut windows = Vec::new();
+        for date in &dates {
+            for valve in self.iter().filter(|v| is_scheduled(v.valve_number)) {
+                windows.extend(
+                    valve
+                        .windows_on(*date, &tz, &env)
+                        .map(|(begin, end)| (begin, end, valve.valve_number)),
+                );
+            }
+            windows.extend(
+                self.program_runs_on(*date)
+                    .filter(|run| is_scheduled(run.valve_number))
+                    .map(|run| (run.begin, run.end, run.valve_number)),
+            );
+        }
+
+        // More valves can only be open once another one begins
+        let mut moments: Vec<_> = windows
+            .iter()
+            .map(|(begin, _, _)| *begin)
+            .filter(|begin| begin > time && *begin < horizon)
+            .collect();
+        moments.push(*time);
+        moments.sort();
+        moments.dedup();
+
+        let mut conflicts: Vec<ConcurrencyConflict> = Vec::new();
+        for moment in moments {
+            let mut valves: Vec<_> = windows
+                .iter()
+                .filter(|(begin, end, _)| *begin <= moment && moment < *end)
+                .map(|(_, _, valve_number)| *valve_number)
+                .collect();
+            valves.sort_unstable();
+            valves.dedup();
+            let repeated = conflicts.last().is_some_and(|last| last.valves == valves);
+            if valves.len() > limit && !repeated {
+                conflicts.push(ConcurrencyConflict {
+                    time: moment,
+                    valves,
+                });
+            }
+        }
+        conflicts
+    }
+
+    /// When the status of any valve might change next without any user interaction.
+    pub fn next_change(&self, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let next_change = self.zone_next_change(time);
+        if !self.iter().any(|valve| valve.role == ValveRole::Master) {
+            return next_change;
+        }
+        // Master valves open ahead of the zones and close after them
+        let lead = master_lead();
+        let ahead = self
+            .zone_next_change(&(*time + lead))
+            .map(|next_change| next_change - lead);
+        let behind = self
+            .zone_next_change(&(*time - lead))
+            .map(|next_change| next_change + lead);
+        next_change.into_iter().chain(ahead).chain(behind).min()
+    }
+
+    fn zone_next_change(&self, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let env = self.environment();
+        let schedules = self
+            .iter()
+            .filter(|valve| valve.is_zone())
+            .filter_map(|valve| valve.next_change(time, &env));
+        // Programs only repeat weekly, so a week from the day before covers all of them
+        let programs = (-1..=7)
+            .map(|offset| time.naive_local().date() + chrono::Duration::days(offset))
+            .flat_map(|date| self.program_runs_on(date))
+            .filter(|run| self.get(run.valve_number).is_some_and(Valve::is_zone))
+            .flat_map(|run| IntoIterator::into_iter([run.begin, run.end]));
+        let deferred = self.zone_plan(time).deferred_changes;
+        schedules
+            .chain(programs)
+            .chain(deferred)
+            .filter(|boundary| boundary > time)
+            .min()
+    }
+}
+
+impl IntoIterator for ControllerConfig {
+    type Item = Valve;
+
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.valves.into_iter()
     }
 }
 
 pub type ServerConfig = Arc<RwLock<ControllerConfig>>;
+
+pub type ControllerId = String;
+
+/// Controller ids end up in URLs and MQTT topics and client ids, so only
+/// ASCII letters, digits, `-` and `_` are allowed.
+pub fn check_controller_id(id: &str) -> Result<(), Error> {
+    if id.is_empty()
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidControllerId);
+    }
+    Ok(())
+}
+
+/// A controller as its handlers and its executor share it.
+#[derive(Clone)]
+pub struct Controller {
+    pub id: ControllerId,
+    pub config: ServerConfig,
+    /// Wakes the executor of this controller after a change.
+    pub wakeup: Arc<Notify>,
+}
+
+impl Controller {
+    pub fn new(id: impl Into<ControllerId>, config: ControllerConfig) -> Self {
+        Controller {
+            id: id.into(),
+            config: Arc::new(RwLock::new(config)),
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Whether `controllers` still holds this controller and not one that
+    /// replaced it under the same id.
+    pub fn is_registered(&self, controllers: &BTreeMap<ControllerId, Controller>) -> bool {
+        controllers
+            .get(&self.id)
+            .is_some_and(|controller| Arc::ptr_eq(&controller.config, &self.config))
+    }
+}
+
+/// All controllers managed by this server, each with its own lock so a
+/// busy one doesn't hold up the others.
+pub type Controllers = Arc<RwLock<BTreeMap<ControllerId, Controller>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_controller_id, AutomationStatus, ControllerConfig, DailySchedule, Duration,
+        Environment, Error, IntervalSchedule, Location, Program, ProgramStep, ScheduleKind, Season,
+        Sensor, Site, SkipReason, SkippedRun, TimeOfDay, Valve, ValveRole, ValveStatus,
+        MAX_SAFETY_EVENTS,
+    };
+    use crate::weather::WeatherAdjustment;
+    use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+    use chrono_tz::{Europe::Berlin, Tz};
+    use reqwest::Url;
+
+    const SITE: Site = Site {
+        timezone: Tz::UTC,
+        location: None,
+    };
+
+    const ENV: Environment = Environment {
+        location: None,
+        water_budget: 100,
+        rain_delay_until: None,
+        weather: None,
+        soil_moisture: None,
+    };
+
+    fn duration(begin: (u32, u32), end: (u32, u32)) -> Duration {
+        Duration::new(
+            NaiveTime::from_hms(begin.0, begin.1, 0),
+            NaiveTime::from_hms(end.0, end.1, 0),
+        )
+        .unwrap()
+    }
+
+    fn utc(date: NaiveDate, hour: u32, min: u32) -> DateTime<Tz> {
+        Tz::UTC.from_utc_datetime(&date.and_hms(hour, min, 0))
+    }
+
+    #[test]
+    fn test_next_change() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Mon, duration((6, 0), (6, 30)), &SITE)
+            .unwrap();
+        // 2021-09-13 is a Monday
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+
+        let begin = utc(monday, 6, 0);
+        assert_eq!(valve.next_change(&utc(monday, 5, 0), &ENV), Some(begin));
+        assert_eq!(valve.valve_status(&begin, &ENV), ValveStatus::Open);
+
+        let end = utc(monday, 6, 30);
+        assert_eq!(valve.next_change(&begin, &ENV), Some(end));
+        assert_eq!(valve.valve_status(&end, &ENV), ValveStatus::Close);
+
+        let next_week = utc(NaiveDate::from_ymd(2021, 9, 20), 6, 0);
+        assert_eq!(valve.next_change(&end, &ENV), Some(next_week));
+
+        valve.automation_status = AutomationStatus::ForceOpen;
+        assert_eq!(valve.next_change(&end, &ENV), None);
+    }
+
+    #[test]
+    fn test_overnight() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Sun, duration((23, 30), (1, 0)), &SITE)
+            .unwrap();
+        let sunday = NaiveDate::from_ymd(2021, 9, 12);
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+
+        assert_eq!(
+            valve.valve_status(&utc(sunday, 23, 45), &ENV),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.valve_status(&utc(monday, 0, 30), &ENV),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.valve_status(&utc(monday, 1, 0), &ENV),
+            ValveStatus::Close
+        );
+        assert_eq!(
+            valve.valve_status(&utc(sunday, 0, 30), &ENV),
+            ValveStatus::Close
+        );
+        assert_eq!(
+            valve.next_change(&utc(sunday, 23, 45), &ENV),
+            Some(utc(monday, 1, 0))
+        );
+
+        // Runs into the spill-over from Sunday
+        assert!(matches!(
+            valve.add_duration(&Weekday::Mon, duration((0, 45), (2, 0)), &SITE),
+            Err(Error::OverlappingDurations)
+        ));
+        valve
+            .add_duration(&Weekday::Sat, duration((22, 0), (23, 45)), &SITE)
+            .unwrap();
+        // Runs into Sunday's entry from the day before
+        assert!(matches!(
+            valve.add_duration(&Weekday::Sat, duration((23, 50), (23, 35)), &SITE),
+            Err(Error::OverlappingDurations)
+        ));
+        valve
+            .add_duration(&Weekday::Mon, duration((1, 30), (2, 0)), &SITE)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_overnight_same_day() {
+        let mut daily_schedule = DailySchedule::default();
+        daily_schedule
+            .add_entry(duration((23, 0), (1, 0)), &SITE)
+            .unwrap();
+        // Only overlaps the entry of the following day, which isn't part of this schedule
+        daily_schedule
+            .add_entry(duration((0, 30), (2, 0)), &SITE)
+            .unwrap();
+        assert!(daily_schedule
+            .add_entry(duration((22, 0), (23, 30)), &SITE)
+            .is_err());
+        assert!(matches!(
+            Duration::new(NaiveTime::from_hms(6, 0, 0), NaiveTime::from_hms(6, 0, 0)),
+            Err(Error::EmptyDuration)
+        ));
+    }
+
+    #[test]
+    fn test_dst_clocks_forward() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Sun, duration((2, 15), (2, 45)), &SITE)
+            .unwrap();
+        // 02:00 CET jumps to 03:00 CEST, the entry is moved to 03:15 CEST
+        let sunday = NaiveDate::from_ymd(2021, 3, 28);
+        let begin = utc(sunday, 1, 15).with_timezone(&Berlin);
+        let end = utc(sunday, 1, 45).with_timezone(&Berlin);
+        let midnight = Berlin
+            .from_local_datetime(&sunday.and_hms(0, 0, 0))
+            .unwrap();
+
+        assert_eq!(valve.next_change(&midnight, &ENV), Some(begin));
+        assert_eq!(valve.next_change(&begin, &ENV), Some(end));
+        assert_eq!(valve.valve_status(&begin, &ENV), ValveStatus::Open);
+        assert_eq!(
+            valve.valve_status(&utc(sunday, 0, 20).with_timezone(&Berlin), &ENV),
+            ValveStatus::Close
+        );
+    }
+
+    #[test]
+    fn test_dst_clocks_back() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Sun, duration((2, 15), (2, 45)), &SITE)
+            .unwrap();
+        // 03:00 CEST goes back to 02:00 CET, the entry only runs during the first 02:15
+        let sunday = NaiveDate::from_ymd(2021, 10, 31);
+        let begin = utc(sunday, 0, 15).with_timezone(&Berlin);
+        let end = utc(sunday, 0, 45).with_timezone(&Berlin);
+        let repeated = utc(sunday, 1, 15).with_timezone(&Berlin);
+        let midnight = Berlin
+            .from_local_datetime(&sunday.and_hms(0, 0, 0))
+            .unwrap();
+
+        assert_eq!(valve.next_change(&midnight, &ENV), Some(begin));
+        assert_eq!(valve.valve_status(&begin, &ENV), ValveStatus::Open);
+        assert_eq!(valve.valve_status(&repeated, &ENV), ValveStatus::Close);
+        let next_week = utc(NaiveDate::from_ymd(2021, 11, 7), 1, 15).with_timezone(&Berlin);
+        assert_eq!(valve.next_change(&end, &ENV), Some(next_week));
+    }
+
+    #[test]
+    fn test_calendar() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Tue, duration((6, 0), (6, 30)), &SITE)
+            .unwrap();
+        let tuesday = NaiveDate::from_ymd(2026, 7, 14);
+        let next_tuesday = NaiveDate::from_ymd(2026, 7, 21);
+        let monday = NaiveDate::from_ymd(2026, 7, 20);
+
+        valve.skip_date(tuesday);
+        assert_eq!(
+            valve.valve_status(&utc(tuesday, 6, 15), &ENV),
+            ValveStatus::Close
+        );
+        assert_eq!(
+            valve.next_change(&utc(tuesday, 5, 0), &ENV),
+            Some(utc(next_tuesday, 6, 0))
+        );
+
+        valve
+            .add_exception(tuesday, duration((7, 0), (7, 30)), &SITE)
+            .unwrap();
+        assert_eq!(
+            valve.valve_status(&utc(tuesday, 7, 15), &ENV),
+            ValveStatus::Open
+        );
+        valve.remove_exception(&tuesday).unwrap();
+        assert_eq!(
+            valve.valve_status(&utc(tuesday, 6, 15), &ENV),
+            ValveStatus::Open
+        );
+        // Without durations the exception is gone as well
+        valve
+            .add_exception(tuesday, duration((7, 0), (7, 30)), &SITE)
+            .unwrap();
+        valve
+            .remove_exception_duration(&tuesday, duration((7, 0), (7, 30)))
+            .unwrap();
+        assert_eq!(
+            valve.valve_status(&utc(tuesday, 6, 15), &ENV),
+            ValveStatus::Open
+        );
+        assert!(matches!(
+            valve.remove_exception(&tuesday),
+            Err(Error::MissingDuration)
+        ));
+
+        valve
+            .add_one_off(monday, duration((6, 0), (6, 30)), &SITE)
+            .unwrap();
+        assert_eq!(
+            valve.valve_status(&utc(monday, 6, 15), &ENV),
+            ValveStatus::Open
+        );
+        // Would run into the weekly entry of the next day
+        assert!(matches!(
+            valve.add_one_off(monday, duration((23, 0), (6, 10)), &SITE),
+            Err(Error::OverlappingDurations)
+        ));
+    }
+
+    #[test]
+    fn test_season() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Mon, duration((6, 0), (6, 30)), &SITE)
+            .unwrap();
+        valve.season = Some(
+            Season::new(
+                NaiveDate::from_ymd(2021, 4, 1),
+                NaiveDate::from_ymd(2021, 10, 31),
+                true,
+            )
+            .unwrap(),
+        );
+        let october = NaiveDate::from_ymd(2026, 10, 26);
+        let november = NaiveDate::from_ymd(2026, 11, 2);
+        assert_eq!(
+            valve.valve_status(&utc(october, 6, 15), &ENV),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.valve_status(&utc(november, 6, 15), &ENV),
+            ValveStatus::Close
+        );
+        assert_eq!(valve.next_change(&utc(october, 6, 30), &ENV), None);
+
+        // Dated runs don't care about the season
+        valve
+            .add_one_off(november, duration((7, 0), (7, 30)), &SITE)
+            .unwrap();
+        assert_eq!(
+            valve.valve_status(&utc(november, 7, 15), &ENV),
+            ValveStatus::Open
+        );
+
+        let winter = Season::new(
+            NaiveDate::from_ymd(2021, 11, 1),
+            NaiveDate::from_ymd(2021, 2, 28),
+            true,
+        )
+        .unwrap();
+        assert!(winter.contains(&NaiveDate::from_ymd(2030, 1, 15)));
+        assert!(!winter.contains(&NaiveDate::from_ymd(2030, 3, 1)));
+        assert!(Season::new(
+            NaiveDate::from_ymd(2021, 11, 1),
+            NaiveDate::from_ymd(2021, 2, 28),
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_interval() {
+        let mut valve = Valve::new("rasen", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve.schedule_kind = ScheduleKind::Interval;
+        let anchor = NaiveDate::from_ymd(2026, 7, 1);
+        let interval = valve.interval_schedule_mut();
+        interval.set_interval(3, anchor, &SITE).unwrap();
+        interval
+            .add_entry(duration((23, 0), (1, 0)), &SITE)
+            .unwrap();
+
+        assert_eq!(
+            valve.valve_status(&utc(anchor, 23, 30), &ENV),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.valve_status(&utc(anchor.succ(), 0, 30), &ENV),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.valve_status(&utc(anchor.succ(), 23, 30), &ENV),
+            ValveStatus::Close
+        );
+        // Also counts backwards from the anchor
+        let before = NaiveDate::from_ymd(2026, 6, 28);
+        assert_eq!(
+            valve.valve_status(&utc(before, 23, 30), &ENV),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.next_change(&utc(anchor.succ(), 1, 0), &ENV),
+            Some(utc(NaiveDate::from_ymd(2026, 7, 4), 23, 0))
+        );
+
+        // Daily runs would overlap with the entry running past midnight
+        let interval = valve.interval_schedule_mut();
+        interval
+            .add_entry(duration((0, 30), (2, 0)), &SITE)
+            .unwrap();
+        assert!(matches!(
+            interval.set_interval(1, anchor, &SITE),
+            Err(Error::OverlappingDurations)
+        ));
+        assert!(matches!(
+            interval.set_interval(0, anchor, &SITE),
+            Err(Error::InvalidInterval)
+        ));
+        let stored = r#"{"every_days": 0, "anchor": "2026-07-01", "entries": []}"#;
+        assert!(serde_json::from_str::<IntervalSchedule>(stored).is_err());
+        assert!(serde_json::from_str::<IntervalSchedule>(&stored.replace("0,", "2,")).is_ok());
+    }
+
+    #[test]
+    fn test_sun_relative() {
+        assert_eq!(
+            "sunset+30".parse::<TimeOfDay>().unwrap(),
+            TimeOfDay::Sunset(30)
+        );
+        assert_eq!(
+            "sunrise".parse::<TimeOfDay>().unwrap(),
+            TimeOfDay::Sunrise(0)
+        );
+        assert_eq!(
+            "06:00".parse::<TimeOfDay>().unwrap(),
+            TimeOfDay::Fixed(NaiveTime::from_hms(6, 0, 0))
+        );
+        assert!("sunrise30".parse::<TimeOfDay>().is_err());
+        assert!("sunset+400".parse::<TimeOfDay>().is_err());
+        assert_eq!(TimeOfDay::Sunrise(-15).to_string(), "sunrise-15");
+
+        let berlin = Site {
+            timezone: Berlin,
+            location: Some(Location::new(52.52, 13.405).unwrap()),
+        };
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        let from_sunrise =
+            Duration::new(TimeOfDay::Sunrise(0), NaiveTime::from_hms(7, 0, 0)).unwrap();
+        valve
+            .add_duration(&Weekday::Mon, from_sunrise, &berlin)
+            .unwrap();
+        // Sunrise is before 05:00 in summer
+        assert!(matches!(
+            valve.add_duration(&Weekday::Mon, duration((5, 0), (5, 30)), &berlin),
+            Err(Error::OverlappingDurations)
+        ));
+        // and after 07:00 in winter, when the entry doesn't run at all
+        valve
+            .add_duration(&Weekday::Mon, duration((7, 30), (8, 0)), &berlin)
+            .unwrap();
+        // Sunrise passes 06:00 in April and September only
+        let mut rasen = Valve::new("rasen", 1);
+        let after_sunrise = Duration::new(TimeOfDay::Sunrise(0), TimeOfDay::Sunrise(10)).unwrap();
+        rasen
+            .add_duration(&Weekday::Tue, after_sunrise, &berlin)
+            .unwrap();
+        assert!(matches!(
+            rasen.add_duration(&Weekday::Tue, duration((6, 0), (6, 10)), &berlin),
+            Err(Error::OverlappingDurations)
+        ));
+
+        // Sunrise in Berlin on 2021-06-21 is at 04:43 CEST
+        let monday = NaiveDate::from_ymd(2021, 6, 21);
+        let env = Environment {
+            location: berlin.location,
+            ..ENV
+        };
+        let at = |hour, min| {
+            Berlin
+                .from_local_datetime(&monday.and_hms(hour, min, 0))
+                .unwrap()
+        };
+        assert_eq!(valve.valve_status(&at(4, 30), &env), ValveStatus::Close);
+        assert_eq!(valve.valve_status(&at(4, 50), &env), ValveStatus::Open);
+        assert_eq!(valve.valve_status(&at(7, 0), &env), ValveStatus::Close);
+        let sunrise = valve.next_change(&at(0, 0), &env).unwrap();
+        assert!((sunrise - at(4, 43)).num_minutes().abs() <= 2);
+
+        // Without a location only the fixed entry runs
+        assert_eq!(valve.valve_status(&at(4, 50), &ENV), ValveStatus::Close);
+        assert_eq!(valve.next_change(&at(0, 0), &ENV), Some(at(7, 30)));
+
+        // Sunrise on 2021-12-20 is at 08:14 CET, past the end at 07:00, so
+        // the entry is skipped instead of running until the next morning
+        let winter = Berlin
+            .from_local_datetime(&NaiveDate::from_ymd(2021, 12, 20).and_hms(9, 0, 0))
+            .unwrap();
+        assert_eq!(valve.valve_status(&winter, &env), ValveStatus::Close);
+    }
+
+    #[test]
+    fn test_run_length() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        let run = Duration::with_run_length(NaiveTime::from_hms(6, 0, 0), 20).unwrap();
+        valve.add_duration(&Weekday::Mon, run, &SITE).unwrap();
+        // Overlaps at full budget
+        assert!(matches!(
+            valve.add_duration(&Weekday::Mon, duration((6, 15), (6, 30)), &SITE),
+            Err(Error::OverlappingDurations)
+        ));
+        assert!(matches!(
+            Duration::with_run_length(NaiveTime::from_hms(6, 0, 0), 0),
+            Err(Error::EmptyDuration)
+        ));
+
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        assert_eq!(
+            valve.next_change(&utc(monday, 6, 0), &ENV),
+            Some(utc(monday, 6, 20))
+        );
+
+        // 120% globally and another 150% for this valve make 36 minutes
+        let heat_wave = Environment {
+            water_budget: 120,
+            ..ENV
+        };
+        valve.set_water_budget(Some(150)).unwrap();
+        assert_eq!(
+            valve.next_change(&utc(monday, 6, 0), &heat_wave),
+            Some(utc(monday, 6, 36))
+        );
+        assert_eq!(
+            valve.valve_status(&utc(monday, 6, 30), &heat_wave),
+            ValveStatus::Open
+        );
+        assert!(matches!(
+            valve.set_water_budget(Some(5000)),
+            Err(Error::InvalidWaterBudget)
+        ));
+        // Nor can a state file get it in
+        let mut stored = serde_json::to_value(&valve).unwrap();
+        assert!(serde_json::from_value::<Valve>(stored.clone()).is_ok());
+        stored["water_budget"] = 5000.into();
+        assert!(serde_json::from_value::<Valve>(stored).is_err());
+    }
+
+    #[test]
+    fn test_program() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        for valve_number in 0..3 {
+            let mut valve = Valve::new("beet", valve_number);
+            valve.automation_status = AutomationStatus::Scheduled;
+            config.push(valve);
+        }
+        config.get_mut(2).unwrap().automation_status = AutomationStatus::ForceClose;
+        let mut program = Program::new("garden", TimeOfDay::Fixed(NaiveTime::from_hms(6, 0, 0)));
+        program.set_weekdays([Weekday::Mon]);
+        program.pause_minutes = 10;
+        let id = config.push_program(program);
+        for (valve_number, run_minutes) in [(0, 20), (2, 10), (1, 15)] {
+            let step = ProgramStep {
+                valve_number,
+                run_minutes,
+            };
+            config.add_program_step(id, step).unwrap();
+        }
+        assert!(matches!(
+            config.add_program_step(
+                id,
+                ProgramStep {
+                    valve_number: 7,
+                    run_minutes: 5
+                }
+            ),
+            Err(Error::InvalidValveNumber)
+        ));
+
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        let status = |valve_number, hour, min| {
+            config.valve_status(config.get(valve_number).unwrap(), &utc(monday, hour, min))
+        };
+        assert_eq!(status(0, 6, 10), ValveStatus::Open);
+        assert_eq!(status(1, 6, 10), ValveStatus::Close);
+        // Forced closed valves keep their slot but stay closed
+        assert_eq!(status(2, 6, 35), ValveStatus::Close);
+        // 20 minutes, 10 pause, 10 for valve 2, 10 pause
+        assert_eq!(status(1, 6, 50), ValveStatus::Open);
+        assert_eq!(status(1, 7, 5), ValveStatus::Close);
+        assert_eq!(
+            config.next_change(&utc(monday, 6, 20)),
+            Some(utc(monday, 6, 30))
+        );
+
+        config.remove_valve(0);
+        assert_eq!(config.program(id).unwrap().steps().len(), 2);
+        config.remove_program(id).unwrap();
+        assert!(config.next_change(&utc(monday, 6, 20)).is_none());
+    }
+
+    #[test]
+    fn test_next_run() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        let mut valve = Valve::new("beet", 0);
+        valve
+            .add_duration(&Weekday::Wed, duration((7, 0), (7, 30)), &SITE)
+            .unwrap();
+        config.push(valve);
+        let mut program = Program::new("garden", TimeOfDay::Fixed(NaiveTime::from_hms(6, 0, 0)));
+        program.set_weekdays([Weekday::Mon]);
+        let id = config.push_program(program);
+        let step = ProgramStep {
+            valve_number: 0,
+            run_minutes: 20,
+        };
+        config.add_program_step(id, step).unwrap();
+
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        let next_run = |hour, min| config.next_run(config.get(0).unwrap(), &utc(monday, hour, min));
+        // Closed valves still report when they would run
+        assert_eq!(next_run(5, 0), Some(utc(monday, 6, 0)));
+        // A run that already started isn't the next one
+        assert_eq!(
+            next_run(6, 10),
+            Some(utc(NaiveDate::from_ymd(2021, 9, 15), 7, 0))
+        );
+    }
+
+    #[test]
+    fn test_max_concurrent_open() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        for (valve_number, begin) in [(0, 0), (1, 10), (2, 20)] {
+            let mut valve = Valve::new("beet", valve_number);
+            valve.automation_status = AutomationStatus::Scheduled;
+            valve
+                .add_duration(&Weekday::Mon, duration((6, begin), (6, begin + 30)), &SITE)
+                .unwrap();
+            config.push(valve);
+        }
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        assert_eq!(config.plan(&utc(monday, 6, 25)).queue, Vec::<u8>::new());
+        assert!(config.concurrency_conflicts(&utc(monday, 5, 0)).is_empty());
+        assert!(matches!(
+            config.set_max_concurrent_open(Some(0)),
+            Err(Error::InvalidLimit)
+        ));
+
+        config.set_max_concurrent_open(Some(2)).unwrap();
+        let conflicts = config.concurrency_conflicts(&utc(monday, 5, 0));
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].time, utc(monday, 6, 20));
+        assert_eq!(conflicts[0].valves, vec![0, 1, 2]);
+
+        config.set_max_concurrent_open(Some(1)).unwrap();
+        let plan = config.plan(&utc(monday, 6, 15));
+        assert_eq!(plan.status(0), ValveStatus::Open);
+        assert_eq!(plan.queue, vec![1]);
+        // The earlier start goes first once the slot is free
+        let plan = config.plan(&utc(monday, 6, 30));
+        assert_eq!(plan.status(1), ValveStatus::Open);
+        assert_eq!(plan.queue, vec![2]);
+
+        // A queued valve runs for the full length of its entry, even when
+        // the entry ends while it waits
+        assert_eq!(
+            config.next_change(&utc(monday, 6, 55)),
+            Some(utc(monday, 7, 0))
+        );
+        let plan = config.plan(&utc(monday, 6, 55));
+        assert_eq!(plan.status(1), ValveStatus::Open);
+        assert_eq!(plan.queue, vec![2]);
+        let plan = config.plan(&utc(monday, 7, 0));
+        assert_eq!(plan.status(1), ValveStatus::Close);
+        assert_eq!(plan.status(2), ValveStatus::Open);
+        assert_eq!(
+            config.next_change(&utc(monday, 7, 0)),
+            Some(utc(monday, 7, 30))
+        );
+        assert_eq!(
+            config.plan(&utc(monday, 7, 30)).status(2),
+            ValveStatus::Close
+        );
+
+        // Forced valves always get a slot first
+        config.get_mut(2).unwrap().automation_status = AutomationStatus::ForceOpen;
+        let plan = config.plan(&utc(monday, 6, 30));
+        assert_eq!(plan.status(2), ValveStatus::Open);
+        assert_eq!(plan.queue, vec![1]);
+    }
+
+    #[test]
+    fn test_master_valve() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        let mut zone = Valve::new("beet", 0);
+        zone.automation_status = AutomationStatus::Scheduled;
+        zone.add_duration(&Weekday::Mon, duration((6, 0), (6, 30)), &SITE)
+            .unwrap();
+        config.push(zone);
+        for (valve_number, role) in [(1, ValveRole::Master), (2, ValveRole::Pump)] {
+            let mut valve = Valve::new("versorgung", valve_number);
+            valve.automation_status = AutomationStatus::Scheduled;
+            valve.role = role;
+            config.push(valve);
+        }
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        let before = utc(monday, 6, 0) - chrono::Duration::seconds(3);
+
+        // The master opens ahead of the zone, the pump waits for it
+        let plan = config.plan(&before);
+        assert_eq!(plan.status(0), ValveStatus::Close);
+        assert_eq!(plan.status(1), ValveStatus::Open);
+        assert_eq!(plan.status(2), ValveStatus::Close);
+        let plan = config.plan(&utc(monday, 6, 15));
+        assert_eq!(plan.status(1), ValveStatus::Open);
+        assert_eq!(plan.status(2), ValveStatus::Open);
+        // The master closes after the zone, the pump with it
+        let plan = config.plan(&utc(monday, 6, 30));
+        assert_eq!(plan.status(0), ValveStatus::Close);
+        assert_eq!(plan.status(1), ValveStatus::Open);
+        assert_eq!(plan.status(2), ValveStatus::Close);
+        let after = utc(monday, 6, 30) + chrono::Duration::seconds(5);
+        assert_eq!(config.next_change(&utc(monday, 6, 30)), Some(after));
+        assert_eq!(config.plan(&after).status(1), ValveStatus::Close);
+        assert_eq!(
+            config.next_change(&utc(monday, 5, 0)),
+            Some(utc(monday, 6, 0) - chrono::Duration::seconds(5))
+        );
+
+        config.get_mut(1).unwrap().automation_status = AutomationStatus::ForceClose;
+        assert_eq!(
+            config.plan(&utc(monday, 6, 15)).status(1),
+            ValveStatus::Close
+        );
+
+        let id = config.push_program(Program::new("runde", NaiveTime::from_hms(5, 0, 0).into()));
+        assert!(matches!(
+            config.add_program_step(
+                id,
+                ProgramStep {
+                    valve_number: 1,
+                    run_minutes: 10
+                }
+            ),
+            Err(Error::InvalidValveRole)
+        ));
+        // Nor can a program step become a master valve
+        config
+            .add_program_step(
+                id,
+                ProgramStep {
+                    valve_number: 0,
+                    run_minutes: 10,
+                },
+            )
+            .unwrap();
+        assert!(matches!(
+            config.set_role(0, ValveRole::Master),
+            Err(Error::InvalidValveRole)
+        ));
+        assert!(config.get(0).unwrap().is_zone());
+        config.set_role(1, ValveRole::Zone).unwrap();
+        assert!(config.get(1).unwrap().is_zone());
+    }
+
+    #[test]
+    fn test_timed_override() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Mon, duration((6, 0), (6, 30)), &SITE)
+            .unwrap();
+        config.push(valve);
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        let until = |hour, min| utc(monday, hour, min).with_timezone(&Utc);
+
+        let valve = config.get_mut(0).unwrap();
+        assert!(matches!(
+            valve.set_automation_status(
+                AutomationStatus::ForceOpenUntil(until(4, 0)),
+                &utc(monday, 5, 0)
+            ),
+            Err(Error::InvalidOverride)
+        ));
+        valve
+            .set_automation_status(
+                AutomationStatus::ForceCloseUntil(until(5, 10)),
+                &utc(monday, 5, 0),
+            )
+            .unwrap();
+        // Replacing an override still returns to the mode before the first
+        valve
+            .set_automation_status(
+                AutomationStatus::ForceOpenUntil(until(5, 20)),
+                &utc(monday, 5, 0),
+            )
+            .unwrap();
+        assert_eq!(
+            valve.valve_status(&utc(monday, 5, 10), &ENV),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.next_change(&utc(monday, 5, 10), &ENV),
+            Some(utc(monday, 5, 20))
+        );
+        // Scheduled again once the override ended, even before it is expired
+        assert_eq!(
+            valve.valve_status(&utc(monday, 5, 25), &ENV),
+            ValveStatus::Close
+        );
+        assert_eq!(
+            valve.next_change(&utc(monday, 5, 25), &ENV),
+            Some(utc(monday, 6, 0))
+        );
+
+        assert!(!config.expire_overrides(&utc(monday, 5, 10)));
+        assert!(config.expire_overrides(&utc(monday, 5, 25)));
+        assert_eq!(
+            *config.get(0).unwrap().automation_status(),
+            AutomationStatus::Scheduled
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut valve = Valve::new("beet", 0);
+        assert!(!valve.drifted());
+        valve.reported_status = Some(ValveStatus::Open);
+        valve.commanded_status = Some(ValveStatus::Open);
+        assert!(!valve.drifted());
+        // Only what was commanded at the time of the reading counts
+        valve.commanded_status = Some(ValveStatus::Close);
+        assert!(valve.drifted());
+    }
+
+    #[test]
+    fn test_controller_id() {
+        assert!(check_controller_id("garten_2-nord").is_ok());
+        for id in ["", "a/b", "a+b", "a#b", "a\0b", "gärten", "a b"] {
+            assert!(matches!(
+                check_controller_id(id),
+                Err(Error::InvalidControllerId)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::ForceOpen;
+        config.push(valve);
+        config.push(Valve::new("rasen", 1));
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+
+        let plan = config.plan(&utc(monday, 6, 0));
+        assert_eq!(config.record_runs(&plan, &utc(monday, 6, 0)), vec![0]);
+        assert!(config.watch(&plan, &utc(monday, 6, 0)).is_empty());
+        // Still the same run
+        assert!(config.record_runs(&plan, &utc(monday, 6, 5)).is_empty());
+        assert_eq!(
+            config.get(0).unwrap().last_run,
+            Some(utc(monday, 6, 0).with_timezone(&Utc))
+        );
+        assert_eq!(config.watchdog_deadline(), None);
+        config.set_max_open_minutes(Some(60)).unwrap();
+        assert_eq!(config.watchdog_deadline(), Some(utc(monday, 7, 0)));
+        // The valve's own limit wins
+        config
+            .get_mut(0)
+            .unwrap()
+            .set_max_open_minutes(Some(20))
+            .unwrap();
+        assert_eq!(config.watchdog_deadline(), Some(utc(monday, 6, 20)));
+
+        let plan = config.plan(&utc(monday, 6, 20));
+        let events = config.watch(&plan, &utc(monday, 6, 20));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].valve_number, 0);
+        assert_eq!(events[0].open_minutes, 20);
+        assert_eq!(
+            *config.get(0).unwrap().automation_status(),
+            AutomationStatus::ForceClose
+        );
+        assert_eq!(
+            config.plan(&utc(monday, 6, 20)).status(0),
+            ValveStatus::Close
+        );
+        assert_eq!(config.watchdog_deadline(), None);
+
+        assert!(!config.safety_events()[0].acknowledged);
+        config.acknowledge_safety_events();
+        assert!(config.safety_events()[0].acknowledged);
+
+        // Master valves following the zones aren't held to the global limit
+        let mut master = Valve::new("hauptventil", 2);
+        master.role = ValveRole::Master;
+        master.automation_status = AutomationStatus::Scheduled;
+        config.push(master);
+        assert_eq!(config.max_open_duration(config.get(2).unwrap()), None);
+        // unless they are forced open
+        config.get_mut(2).unwrap().automation_status = AutomationStatus::ForceOpen;
+        assert_eq!(
+            config.max_open_duration(config.get(2).unwrap()),
+            Some(chrono::Duration::minutes(60))
+        );
+        config
+            .get_mut(2)
+            .unwrap()
+            .set_max_open_minutes(Some(240))
+            .unwrap();
+        assert_eq!(
+            config.max_open_duration(config.get(2).unwrap()),
+            Some(chrono::Duration::minutes(240))
+        );
+
+        // Acknowledged events make room for new ones
+        let event = config.safety_events()[0].clone();
+        config.safety_events = vec![event; MAX_SAFETY_EVENTS + 10];
+        config.safety_events[3].acknowledged = false;
+        config.prune_safety_events();
+        assert_eq!(config.safety_events().len(), MAX_SAFETY_EVENTS);
+        // The unacknowledged one is kept, now the oldest
+        assert!(!config.safety_events()[0].acknowledged);
+    }
+
+    #[test]
+    fn test_rain_delay() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        for (valve_number, automation_status) in [
+            (0, AutomationStatus::Scheduled),
+            (1, AutomationStatus::ForceOpen),
+        ] {
+            let mut valve = Valve::new("beet", valve_number);
+            valve.automation_status = automation_status;
+            for weekday in [Weekday::Mon, Weekday::Tue] {
+                valve
+                    .add_duration(&weekday, duration((6, 0), (6, 30)), &SITE)
+                    .unwrap();
+            }
+            config.push(valve);
+        }
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        let tuesday = monday.succ();
+
+        assert!(matches!(
+            config.delay_for_rain(0, &utc(monday, 0, 0)),
+            Err(Error::InvalidRainDelay)
+        ));
+        assert!(matches!(
+            config.delay_for_rain(u32::MAX, &utc(monday, 0, 0)),
+            Err(Error::InvalidRainDelay)
+        ));
+        config.delay_for_rain(24, &utc(monday, 0, 0)).unwrap();
+        let plan = config.plan(&utc(monday, 6, 15));
+        assert_eq!(plan.status(0), ValveStatus::Close);
+        assert_eq!(plan.status(1), ValveStatus::Open);
+        assert_eq!(
+            config.next_change(&utc(monday, 5, 0)),
+            Some(utc(tuesday, 0, 0))
+        );
+        assert_eq!(
+            config.plan(&utc(tuesday, 6, 15)).status(0),
+            ValveStatus::Open
+        );
+        assert_eq!(config.rain_delay_until(&utc(tuesday, 6, 15)), None);
+
+        config.cancel_rain_delay();
+        assert_eq!(
+            config.plan(&utc(monday, 6, 15)).status(0),
+            ValveStatus::Open
+        );
+    }
+
+    #[test]
+    fn test_weather() {
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        for weekday in [Weekday::Mon, Weekday::Tue] {
+            let run = Duration::with_run_length(NaiveTime::from_hms(6, 0, 0), 20).unwrap();
+            valve.add_duration(&weekday, run, &SITE).unwrap();
+        }
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+        let tuesday = monday.succ();
+        let mut weather = WeatherAdjustment {
+            date: monday,
+            percent: 150,
+            skip: false,
+        };
+        let env = Environment {
+            weather: Some(weather),
+            ..ENV
+        };
+        // Only applies on its own date
+        assert_eq!(
+            valve.valve_status(&utc(monday, 6, 25), &env),
+            ValveStatus::Open
+        );
+        assert_eq!(
+            valve.next_change(&utc(monday, 6, 25), &env),
+            Some(utc(monday, 6, 30))
+        );
+        assert_eq!(
+            valve.valve_status(&utc(tuesday, 6, 25), &env),
+            ValveStatus::Close
+        );
+
+        weather.skip = true;
+        let env = Environment {
+            weather: Some(weather),
+            ..ENV
+        };
+        assert_eq!(
+            valve.valve_status(&utc(monday, 6, 10), &env),
+            ValveStatus::Close
+        );
+        assert_eq!(
+            valve.next_change(&utc(monday, 5, 0), &env),
+            Some(utc(tuesday, 0, 0))
+        );
+        assert_eq!(
+            valve.valve_status(&utc(tuesday, 6, 10), &env),
+            ValveStatus::Open
+        );
+    }
+
+    #[test]
+    fn test_soil_moisture() {
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        let mut valve = Valve::new("beet", 0);
+        valve.automation_status = AutomationStatus::Scheduled;
+        valve
+            .add_duration(&Weekday::Mon, duration((6, 0), (6, 30)), &SITE)
+            .unwrap();
+        valve.set_moisture_threshold(Some(40.0)).unwrap();
+        config.push(valve);
+        config.add_sensor(Sensor::new("s1", "beet")).unwrap();
+        assert!(matches!(
+            config.add_sensor(Sensor::new("s1", "doppelt")),
+            Err(Error::InvalidSensorId)
+        ));
+        assert!(matches!(
+            config.set_sensor_valves("s1", vec![3]),
+            Err(Error::InvalidValveNumber)
+        ));
+        config.set_sensor_valves("s1", vec![0]).unwrap();
+        let monday = NaiveDate::from_ymd(2021, 9, 13);
+
+        let sensor = config.sensor_mut("s1").unwrap();
+        assert!(matches!(
+            sensor.record(120.0, &utc(monday, 5, 0)),
+            Err(Error::InvalidMoisture)
+        ));
+        sensor.record(55.0, &utc(monday, 5, 0)).unwrap();
+        assert_eq!(
+            config.plan(&utc(monday, 6, 10)).status(0),
+            ValveStatus::Close
+        );
+        assert_eq!(config.record_skips(&utc(monday, 6, 10)), vec![0]);
+        assert_eq!(
+            config.get(0).unwrap().last_skip,
+            Some(SkippedRun {
+                begin: utc(monday, 6, 0).with_timezone(&Utc),
+                reason: SkipReason::SoilWet {
+                    sensor: "s1".to_owned(),
+                    moisture: 55.0
+                }
+            })
+        );
+        // Recorded once per run
+        assert!(config.record_skips(&utc(monday, 6, 20)).is_empty());
+
+        // Dry soil waters again
+        let sensor = config.sensor_mut("s1").unwrap();
+        sensor.record(30.0, &utc(monday, 6, 10)).unwrap();
+        assert_eq!(
+            config.plan(&utc(monday, 6, 15)).status(0),
+            ValveStatus::Open
+        );
+
+        // So does a wet reading that is too old to trust
+        let sensor = config.sensor_mut("s1").unwrap();
+        sensor.record(55.0, &utc(monday.pred(), 6, 10)).unwrap();
+        assert_eq!(
+            config.plan(&utc(monday, 6, 15)).status(0),
+            ValveStatus::Open
+        );
+    }
+}
@@ -1,38 +1,379 @@
-use crate::datamodel::{Error, ServerConfig, Valve, ValveStatus};
-use chrono::{Local, NaiveDateTime};
-use reqwest::{Client, Url};
+use crate::datamodel::{
+    ControllerConfig, ControllerId, Controllers, Error, ValveNumber, ValveRole, ValveStatus,
+};
+use crate::persistence::StateFile;
+use crate::transport::ValveTransport;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::time::{sleep, Duration};
 
-pub async fn control_valves(config: ServerConfig) -> ! {
-    let client = reqwest::Client::new();
+/// How often a command is sent before the valve is considered failed for this tick.
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the first retry, doubled for every further attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// How long to wait before trying failed valves again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the valve states reported by the controller are checked.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Drives the controller `id`: sends the valves whose status changed, reads
+/// back what the controller reports and then sleeps until the next schedule
+/// boundary, the next reconciliation or until its wakeup signals a config
+/// change. Timed overrides that ran out are ended, valves open for too long
+/// are forced closed and skipped runs are recorded, all of it is saved to
+/// `state_file`. Every controller has an executor of its own, it closes all
+/// valves and stops once the controller is removed. The transport is created
+/// anew whenever its settings change.
+pub async fn control_valves(
+    controllers: Controllers,
+    id: ControllerId,
+    state_file: Arc<StateFile>,
+) {
+    let controller = match controllers.read().await.get(&id) {
+        Some(controller) => controller.clone(),
+        None => return,
+    };
+    let (config, wakeup) = (controller.config.clone(), controller.wakeup.clone());
+    let mut transport = None;
+    // What the controller was last successfully told, a missing entry forces a resend
+    let mut delivered: HashMap<ValveNumber, ValveStatus> = HashMap::new();
+    let mut time: DateTime<Utc> = Utc::now();
     loop {
-        sleep(Duration::from_secs(60)).await;
-        let config = config.read().await;
-
-        let time: NaiveDateTime = Local::now().naive_local();
-        for valve in (*config).iter() {
-            send_valve_status(&client, config.address.clone(), valve, time)
-                .await
-                .unwrap();
+        if !controller.is_registered(&*controllers.read().await) {
+            tracing::info!("Controller {} was removed, stopping its executor", id);
+            if let Some((_, transport)) = &transport {
+                close_all(
+                    Arc::<dyn ValveTransport>::as_ref(transport),
+                    &*config.read().await,
+                )
+                .await;
+            }
+            return;
+        }
+        // Don't hold the lock while talking to the controller, the handlers
+        // would be blocked for the whole duration of the retries.
+        let (transport, local_time, desired, roles, next_change) = {
+            let mut config = config.write().await;
+            let settings = (config.transport().clone(), config.address.clone());
+            let transport = match &transport {
+                Some((built_from, transport)) if *built_from == settings => Arc::clone(transport),
+                _ => {
+                    let built = settings.0.build(&id, &settings.1);
+                    transport = Some((settings, built.clone()));
+                    // A new transport may talk to a controller that knows nothing yet
+                    delivered.clear();
+                    built
+                }
+            };
+            let local_time = time.with_timezone(&config.timezone);
+            let mut changed = config.expire_overrides(&local_time);
+            delivered.retain(|valve_number, _| config.get(*valve_number).is_some());
+            let mut plan = config.plan(&local_time);
+            if !config.record_runs(&plan, &local_time).is_empty() {
+                changed = true;
+            }
+            let events = config.watch(&plan, &local_time);
+            for event in &events {
+                tracing::warn!(
+                    "Valve {} was open for {} minutes, forcing it closed",
+                    event.valve_number,
+                    event.open_minutes
+                );
+            }
+            if !events.is_empty() {
+                plan = config.plan(&local_time);
+                changed = true;
+            }
+            for valve_number in config.record_skips(&local_time) {
+                if let Some(skip) = config.get(valve_number).and_then(|v| v.last_skip.as_ref()) {
+                    tracing::info!(
+                        "Valve {} skips its run from {}: {:?}",
+                        valve_number,
+                        skip.begin,
+                        skip.reason
+                    );
+                }
+                changed = true;
+            }
+            if changed {
+                // Holding the registry keeps the controller from being
+                // removed while it is written back
+                let registry = controllers.read().await;
+                if controller.is_registered(&registry) {
+                    if let Err(e) = state_file.save(&id, &config).await {
+                        tracing::error!("Failed to save the state file: {}", e);
+                    }
+                }
+            }
+            if !plan.queue.is_empty() {
+                tracing::debug!("Valves waiting for a free slot: {:?}", plan.queue);
+            }
+            let desired = plan.statuses().clone();
+            let roles: HashMap<_, _> = config
+                .iter()
+                .map(|valve| (valve.valve_number, valve.role))
+                .collect();
+            let next_change = config
+                .next_change(&local_time)
+                .into_iter()
+                .chain(config.watchdog_deadline())
+                .min()
+                .map(|next_change| next_change.with_timezone(&Utc));
+            (transport, local_time, desired, roles, next_change)
+        };
+
+        let commands: Vec<_> = desired
+            .iter()
+            .filter(|(valve_number, status)| delivered.get(valve_number) != Some(status))
+            .map(|(valve_number, status)| (*valve_number, status.clone()))
+            .collect();
+        let (mut results, mut reported) = match transport.health().await {
+            Ok(()) => exchange(transport.as_ref(), commands, &desired, &roles).await,
+            Err(e) => {
+                // Don't wait through the retries of every valve, count them all as failed
+                tracing::warn!("Controller {} is unreachable: {}", id, e);
+                let unreachable = || Error::Unreachable(e.to_string());
+                (
+                    commands
+                        .into_iter()
+                        .map(|(valve_number, status)| (valve_number, (status, Err(unreachable()))))
+                        .collect(),
+                    desired
+                        .keys()
+                        .map(|valve_number| (*valve_number, Err(unreachable())))
+                        .collect(),
+                )
+            }
+        };
+        let failed = results.values().any(|(_, result)| result.is_err());
+
+        {
+            let mut config = config.write().await;
+            for valve in config.iter_mut() {
+                if let Some(result) = reported.remove(&valve.valve_number) {
+                    valve.commanded_status = desired.get(&valve.valve_number).cloned();
+                    match result {
+                        Ok(status) => valve.reported_status = Some(status),
+                        Err(e) => {
+                            tracing::debug!(
+                                "Failed to read back valve {}: {}",
+                                valve.valve_number,
+                                e
+                            );
+                            valve.reported_status = None;
+                        }
+                    }
+                }
+                if let Some((status, result)) = results.remove(&valve.valve_number) {
+                    if result.is_ok() {
+                        delivered.insert(valve.valve_number, status);
+                    } else {
+                        delivered.remove(&valve.valve_number);
+                    }
+                    valve.health.record(local_time, result);
+                }
+            }
         }
+
+        let now = Utc::now();
+        let mut wait = next_change
+            .map(|next_change| (next_change - now).to_std().unwrap_or_default())
+            .unwrap_or(RECONCILE_INTERVAL)
+            .min(RECONCILE_INTERVAL);
+        if failed {
+            wait = wait.min(RETRY_INTERVAL);
+        }
+        let deadline = now + chrono::Duration::from_std(wait).unwrap();
+        tokio::select! {
+            _ = sleep(wait) => {
+                // The timer may fire a hair early, evaluate at the boundary itself
+                time = Utc::now().max(deadline);
+            }
+            _ = wakeup.notified() => {
+                time = Utc::now();
+            }
+        }
+    }
+}
+
+/// Closes every valve of a controller that is no longer driven, so nothing
+/// keeps watering without a schedule or watchdog.
+async fn close_all(transport: &dyn ValveTransport, config: &ControllerConfig) {
+    let commands = config
+        .iter()
+        .map(|valve| (valve.valve_number, ValveStatus::Close))
+        .collect();
+    let roles = config
+        .iter()
+        .map(|valve| (valve.valve_number, valve.role))
+        .collect();
+    for (valve_number, (_, result)) in deliver_in_order(transport, commands, &roles).await {
+        if let Err(e) = result {
+            tracing::error!("Failed to close valve {}: {}", valve_number, e);
+        }
+    }
+}
+
+/// Sends `commands`, reads back the state of every valve in `desired` and
+/// resends what the controller got wrong.
+async fn exchange(
+    transport: &dyn ValveTransport,
+    commands: Vec<(ValveNumber, ValveStatus)>,
+    desired: &HashMap<ValveNumber, ValveStatus>,
+    roles: &HashMap<ValveNumber, ValveRole>,
+) -> (
+    HashMap<ValveNumber, (ValveStatus, Result<(), Error>)>,
+    HashMap<ValveNumber, Result<ValveStatus, Error>>,
+) {
+    let mut results = deliver_in_order(transport, commands, roles).await;
+
+    let reported =
+        join_all(desired.keys().map(|valve_number| async move {
+            (*valve_number, transport.read(*valve_number).await)
+        }))
+        .await;
+    let reported: HashMap<_, _> = reported.into_iter().collect();
+
+    // Whatever the controller did instead of what it was told gets told again
+    let drifted = reported
+        .iter()
+        .filter_map(|(valve_number, result)| {
+            let actual = result.as_ref().ok()?;
+            let commanded = desired.get(valve_number)?;
+            if actual == commanded {
+                return None;
+            }
+            tracing::warn!(
+                "Valve {} is reported {:?} but was commanded {:?}",
+                valve_number,
+                actual,
+                commanded
+            );
+            Some((*valve_number, commanded.clone()))
+        })
+        .collect();
+    results.extend(deliver_in_order(transport, drifted, roles).await);
+    (results, reported)
+}
+
+/// When a command is sent relative to the others of the same tick: master
+/// valves open before and close after the zones, pumps the other way round.
+fn phase(role: ValveRole, status: &ValveStatus) -> u8 {
+    match (role, status) {
+        (ValveRole::Master, ValveStatus::Open) | (ValveRole::Pump, ValveStatus::Close) => 0,
+        (ValveRole::Zone, _) => 1,
+        (ValveRole::Master, ValveStatus::Close) | (ValveRole::Pump, ValveStatus::Open) => 2,
+    }
+}
+
+/// Sends `commands` phase by phase, each phase concurrently.
+async fn deliver_in_order(
+    transport: &dyn ValveTransport,
+    commands: Vec<(ValveNumber, ValveStatus)>,
+    roles: &HashMap<ValveNumber, ValveRole>,
+) -> HashMap<ValveNumber, (ValveStatus, Result<(), Error>)> {
+    let mut results = HashMap::new();
+    for current in 0..=2 {
+        let batch = commands
+            .iter()
+            .filter(|(valve_number, status)| {
+                let role = roles.get(valve_number).copied().unwrap_or_default();
+                phase(role, status) == current
+            })
+            .cloned()
+            .collect();
+        results.extend(deliver(transport, batch).await);
     }
+    results
 }
 
-async fn send_valve_status(
-    client: &Client,
-    url: Url,
-    valve: &Valve,
-    time: NaiveDateTime,
+/// Sends all `commands` concurrently, returning each command with its outcome.
+async fn deliver(
+    transport: &dyn ValveTransport,
+    commands: Vec<(ValveNumber, ValveStatus)>,
+) -> HashMap<ValveNumber, (ValveStatus, Result<(), Error>)> {
+    join_all(
+        commands
+            .into_iter()
+            .map(|(valve_number, status)| async move {
+                let result = send_with_retry(transport, valve_number, status.clone()).await;
+                (valve_number, (status, result))
+            }),
+    )
+    .await
+    .into_iter()
+    .collect()
+}
+
+async fn send_with_retry(
+    transport: &dyn ValveTransport,
+    valve_number: ValveNumber,
+    status: ValveStatus,
 ) -> Result<(), Error> {
-    let url = url
-        .join("/valves/")
-        .and_then(|url| url.join(&valve.valve_number.to_string()))
-        .unwrap();
-    let body = match valve.valve_status(time) {
-        ValveStatus::Open => "open",
-        ValveStatus::Close => "closed",
-    };
-    let builder = client.put(url).body(body);
-    builder.build()?;
-    Ok(())
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match transport.set(valve_number, &status).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= MAX_ATTEMPTS => {
+                tracing::warn!(
+                    "Giving up on valve {} after {} attempts: {}",
+                    valve_number,
+                    attempt,
+                    e
+                );
+                return Err(e);
+            }
+            Err(e) => {
+                tracing::debug!(
+                    "Attempt {} for valve {} failed: {}",
+                    attempt,
+                    valve_number,
+                    e
+                );
+            }
+        }
+        // Spread out the retries so the valves don't hit the controller in lockstep
+        let jitter = rand::thread_rng().gen_range(0..=backoff.as_millis() as u64 / 2);
+        sleep(backoff + Duration::from_millis(jitter)).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::exchange;
+    use crate::datamodel::{ValveRole, ValveStatus};
+    use crate::transport::{SimulatedTransport, ValveTransport};
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_simulated_exchange() {
+        let transport = SimulatedTransport::default();
+        // Valve 2 was opened behind our back
+        transport.set(2, &ValveStatus::Open).await.unwrap();
+        let desired: HashMap<_, _> = IntoIterator::into_iter([
+            (1, ValveStatus::Open),
+            (2, ValveStatus::Close),
+            (3, ValveStatus::Open),
+        ])
+        .collect();
+        let roles: HashMap<_, _> = IntoIterator::into_iter([(3, ValveRole::Master)]).collect();
+        let commands = vec![(1, ValveStatus::Open), (3, ValveStatus::Open)];
+
+        let (results, reported) = exchange(&transport, commands, &desired, &roles).await;
+        assert!(results.values().all(|(_, result)| result.is_ok()));
+        // The drifted valve is told again
+        assert_eq!(results[&2].0, ValveStatus::Close);
+        assert_eq!(reported[&2].as_ref().unwrap(), &ValveStatus::Open);
+        for (valve_number, status) in &desired {
+            assert_eq!(&transport.read(*valve_number).await.unwrap(), status);
+        }
+        // Valves it never heard of are closed
+        assert_eq!(transport.read(4).await.unwrap(), ValveStatus::Close);
+    }
 }
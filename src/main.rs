@@ -1,3 +1,4 @@
+use chrono_tz::Tz;
 use executor::control_valves;
 use hyper::server::Server;
 use listenfd::ListenFd;
@@ -16,10 +17,19 @@ use paths::get_dynamic_paths;
 mod hb;
 
 mod datamodel;
-use datamodel::{ControllerConfig, ServerConfig, Valve};
+use datamodel::{Controller, ControllerConfig, Controllers};
 
 mod executor;
 
+mod mqtt;
+mod persistence;
+mod sun;
+mod transport;
+mod weather;
+use mqtt::run_mqtt;
+use persistence::{StateFile, DEFAULT_CONTROLLER};
+use weather::poll_weather;
+
 use tracing_subscriber::fmt::format::FmtSpan;
 
 #[tokio::main]
@@ -45,8 +55,50 @@ async fn main() {
     // Turn Handlebars instance into a Filter so we can combine it
     // easily with others...
     let hb = Arc::new(hb);
-    let config = get_sample_config();
-    let dynamic_paths = get_dynamic_paths(hb.clone(), config.clone());
+    let default_timezone = match std::env::var("TIMEZONE") {
+        Ok(timezone) => match timezone.parse() {
+            Ok(timezone) => timezone,
+            Err(e) => {
+                tracing::error!("Invalid TIMEZONE: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Err(_) => Tz::UTC,
+    };
+    let mut state_file =
+        StateFile::new(std::env::var("STATE_FILE").unwrap_or_else(|_| "state.json".to_owned()));
+    let controllers = match load_controllers(&mut state_file, default_timezone) {
+        Ok(controllers) => controllers,
+        Err(e) => {
+            tracing::error!(
+                "Failed to load the state file {}: {}",
+                state_file.path().display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    let weather_provider = match weather::provider_from_env() {
+        Ok(provider) => provider,
+        Err(e) => {
+            tracing::error!("Invalid weather provider: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mqtt_settings = match mqtt::settings_from_env() {
+        Ok(settings) => settings,
+        Err(e) => {
+            tracing::error!("Invalid MQTT broker: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let state_file = Arc::new(state_file);
+    let dynamic_paths = get_dynamic_paths(
+        hb.clone(),
+        controllers.clone(),
+        state_file.clone(),
+        default_timezone,
+    );
     let static_content = warp::get()
         .and(warp::path("static"))
         .and(warp::fs::dir("./static/"));
@@ -74,21 +126,52 @@ async fn main() {
     } else {
         Server::bind(&([127, 0, 0, 1], 3030).into())
     };
-    let bg_task = tokio::spawn(control_valves(config.clone()));
+    if let Some(provider) = weather_provider {
+        tokio::spawn(poll_weather(
+            provider,
+            controllers.clone(),
+            state_file.clone(),
+        ));
+    }
+    if let Some(settings) = mqtt_settings {
+        tokio::spawn(run_mqtt(settings, controllers.clone(), state_file.clone()));
+    }
+    // Every controller is driven on its own, so an unreachable one doesn't
+    // hold up the others
+    for id in controllers.read().await.keys() {
+        tokio::spawn(control_valves(
+            controllers.clone(),
+            id.clone(),
+            state_file.clone(),
+        ));
+    }
     server.serve(make_svc).await.unwrap();
-    bg_task.await.unwrap();
 }
 
-pub fn get_sample_config() -> ServerConfig {
-    let url = Url::parse("https://localhost:4040").unwrap();
-    let mut config: ControllerConfig = ControllerConfig::new(url);
-    for valve in vec![
-        Valve::new("blub", 0),
-        Valve::new("test", 1),
-        Valve::new("new", 2),
-    ] {
-        config.push(valve)
-    }
-
-    Arc::new(RwLock::new(config))
+/// Loads the controllers from the state file, starting out with a single
+/// controller without any valves in `timezone` if there is none yet.
+fn load_controllers(
+    state_file: &mut StateFile,
+    timezone: Tz,
+) -> Result<Controllers, datamodel::Error> {
+    let configs = match state_file.load()? {
+        Some(configs) => configs,
+        None => {
+            let address = std::env::var("CONTROLLER_ADDRESS")
+                .unwrap_or_else(|_| "https://localhost:4040".to_owned());
+            let address = Url::parse(&address).map_err(datamodel::Error::InvalidAddress)?;
+            tracing::info!(
+                "No state file found at {}, starting with an empty config",
+                state_file.path().display()
+            );
+            let mut config = ControllerConfig::new(address);
+            config.timezone = timezone;
+            IntoIterator::into_iter([(DEFAULT_CONTROLLER.to_owned(), config)]).collect()
+        }
+    };
+    let controllers = configs
+        .into_iter()
+        .map(|(id, config)| (id.clone(), Controller::new(id, config)))
+        .collect();
+    Ok(Arc::new(RwLock::new(controllers)))
 }
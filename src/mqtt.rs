@@ -0,0 +1,501 @@
+//! Puts the valves on an MQTT bus: their state is published as retained
+//! topics and their modes can be changed through command topics.
+//!
+//! For every valve `<prefix>/<controller>/valves/<valve>/` holds
+//! - `status`: `open` or `closed`
+//! - `automation`: `ForceOpen`, `Scheduled` or `ForceClose`
+//! - `next_run`: when the schedule starts it next, empty if never
+//! - `last_run`: when it last opened, empty if never
+//!
+//! and listens on
+//! - `valve/set`: `open` or `closed` to force the valve open or closed
+//! - `automation/set`: a mode as above or the JSON accepted by
+//!   `POST /controllers/:cid/valves/:id/status`
+//! - `run/set`: minutes to force the valve open for
+//!
+//! Home Assistant discovers the valves through the retained configs under
+//! the discovery prefix, each valve becomes a device with a switch, a mode
+//! select and sensors for its next and last run.
+
+use crate::datamodel::{
+    AutomationStatus, ControllerConfig, ControllerId, Controllers, Error, Valve, ValveNumber,
+    ValveStatus,
+};
+use crate::paths::change_automation_status;
+use crate::persistence::StateFile;
+use chrono::Utc;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::time::{interval, sleep, Duration};
+
+/// How often the published state is compared to the current one.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait before connecting to the broker again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Where the broker is and under which prefix the topics live.
+#[derive(Debug, Clone)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+    /// Has to be unique on the broker, the broker drops the older of two
+    /// connections with the same id.
+    pub client_id: String,
+    /// Where Home Assistant looks for discovery configs, `None` to publish
+    /// none.
+    pub discovery_prefix: Option<String>,
+}
+
+/// The broker configured through `MQTT_HOST`, `MQTT_PORT` (1883 if unset)
+/// and `MQTT_PREFIX` (`sprenkler` if unset), if any. The client id is taken
+/// from `MQTT_CLIENT_ID`, or made up from the prefix. Discovery configs go
+/// below `MQTT_DISCOVERY_PREFIX`, `homeassistant` if unset, an empty value
+/// turns them off.
+pub fn settings_from_env() -> Result<Option<MqttSettings>, Error> {
+    let host = match std::env::var("MQTT_HOST") {
+        Ok(host) => host,
+        Err(_) => return Ok(None),
+    };
+    let port = match std::env::var("MQTT_PORT") {
+        Ok(port) => port.parse().map_err(|_| Error::InvalidTransport)?,
+        Err(_) => 1883,
+    };
+    let prefix = std::env::var("MQTT_PREFIX").unwrap_or_else(|_| "sprenkler".to_owned());
+    let prefix = prefix.trim_matches('/').to_owned();
+    if host.trim().is_empty() || prefix.is_empty() {
+        return Err(Error::InvalidTransport);
+    }
+    let client_id = std::env::var("MQTT_CLIENT_ID")
+        .unwrap_or_else(|_| format!("sprenkler-server-{}", prefix.replace('/', "-")));
+    let discovery_prefix = std::env::var("MQTT_DISCOVERY_PREFIX")
+        .unwrap_or_else(|_| "homeassistant".to_owned())
+        .trim_matches('/')
+        .to_owned();
+    Ok(Some(MqttSettings {
+        host,
+        port,
+        prefix,
+        client_id,
+        discovery_prefix: (!discovery_prefix.is_empty()).then_some(discovery_prefix),
+    }))
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Force the valve open or closed.
+    Valve(ValveStatus),
+    Automation(AutomationStatus),
+    /// Force the valve open for that many minutes.
+    Run(u32),
+}
+
+/// The controller, valve and command a message on `topic` is meant for.
+fn parse_command(
+    prefix: &str,
+    topic: &str,
+    payload: &str,
+) -> Result<(ControllerId, ValveNumber, Command), Error> {
+    let parts: Vec<_> = topic
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .map(|rest| rest.split('/').collect())
+        .unwrap_or_default();
+    let (controller, valve_number, command) = match parts.as_slice() {
+        [controller, "valves", valve_number, command, "set"] => (controller, valve_number, command),
+        _ => return Err(Error::InvalidControllerResponse(topic.to_owned())),
+    };
+    let valve_number = valve_number
+        .parse()
+        .map_err(|_| Error::InvalidValveNumber)?;
+    let payload = payload.trim();
+    let command = match *command {
+        "valve" => Command::Valve(match payload {
+            "open" => ValveStatus::Open,
+            "closed" => ValveStatus::Close,
+            _ => return Err(Error::InvalidControllerResponse(payload.to_owned())),
+        }),
+        "automation" => Command::Automation(parse_automation_status(payload)?),
+        "run" => Command::Run(payload.parse().map_err(|_| Error::InvalidOverride)?),
+        _ => return Err(Error::InvalidControllerResponse(topic.to_owned())),
+    };
+    Ok((controller.to_string(), valve_number, command))
+}
+
+/// Takes both a bare mode like `ForceOpen` and the JSON of the HTTP API.
+fn parse_automation_status(payload: &str) -> Result<AutomationStatus, Error> {
+    serde_json::from_str(payload)
+        .or_else(|_| serde_json::from_value(serde_json::Value::String(payload.to_owned())))
+        .map_err(Error::from)
+}
+
+fn valve_topic(prefix: &str, id: &str, valve_number: ValveNumber, name: &str) -> String {
+    format!("{}/{}/valves/{}/{}", prefix, id, valve_number, name)
+}
+
+/// The Home Assistant discovery configs of `valve`, by topic.
+fn discovery(
+    settings: &MqttSettings,
+    discovery_prefix: &str,
+    id: &str,
+    config: &ControllerConfig,
+    valve: &Valve,
+) -> HashMap<String, String> {
+    let topic = |name| valve_topic(&settings.prefix, id, valve.valve_number, name);
+    // Home Assistant only takes letters, digits, `_` and `-` in ids
+    let node_id: String = format!("{}_{}_{}", settings.prefix, id, valve.valve_number)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let controller_name = if config.name.is_empty() {
+        id
+    } else {
+        &config.name
+    };
+    let device = json!({
+        "identifiers": [node_id],
+        "name": valve.name,
+        "manufacturer": "Sprenkler",
+        "model": format!("Ventil {} von {}", valve.valve_number, controller_name),
+    });
+    let entity = |object_id: &str, entity: serde_json::Value| {
+        let mut entity = entity;
+        entity["unique_id"] = json!(format!("{}_{}", node_id, object_id));
+        entity["availability_topic"] = json!(format!("{}/status", settings.prefix));
+        entity["device"] = device.clone();
+        entity
+    };
+    IntoIterator::into_iter([
+        (
+            "switch",
+            "valve",
+            entity(
+                "valve",
+                json!({
+                    "name": null,
+                    "icon": "mdi:sprinkler",
+                    "state_topic": topic("status"),
+                    "command_topic": topic("valve/set"),
+                    "state_on": "open",
+                    "state_off": "closed",
+                    "payload_on": "open",
+                    "payload_off": "closed",
+                }),
+            ),
+        ),
+        (
+            "select",
+            "automation",
+            entity(
+                "automation",
+                json!({
+                    "name": "Betriebsmodus",
+                    "state_topic": topic("automation"),
+                    "command_topic": topic("automation/set"),
+                    "options": ["ForceOpen", "Scheduled", "ForceClose"],
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "next_run",
+            entity(
+                "next_run",
+                json!({
+                    "name": "Nächster Lauf",
+                    "device_class": "timestamp",
+                    "state_topic": topic("next_run"),
+                    "value_template": "{{ value or None }}",
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "last_run",
+            entity(
+                "last_run",
+                json!({
+                    "name": "Letzter Lauf",
+                    "device_class": "timestamp",
+                    "state_topic": topic("last_run"),
+                    "value_template": "{{ value or None }}",
+                }),
+            ),
+        ),
+    ])
+    .map(|(component, object_id, entity)| {
+        (
+            format!(
+                "{}/{}/{}/{}/config",
+                discovery_prefix, component, node_id, object_id
+            ),
+            entity.to_string(),
+        )
+    })
+    .collect()
+}
+
+/// The retained topics and their payloads for all valves, including their
+/// discovery configs.
+async fn current_state(
+    settings: &MqttSettings,
+    controllers: &Controllers,
+) -> HashMap<String, String> {
+    let controllers: Vec<_> = controllers.read().await.values().cloned().collect();
+    let mut state = HashMap::new();
+    for controller in controllers {
+        let config = controller.config.read().await;
+        let time = config.now();
+        let plan = config.plan(&time);
+        for valve in config.iter() {
+            let topic =
+                |name| valve_topic(&settings.prefix, &controller.id, valve.valve_number, name);
+            let status = match plan.status(valve.valve_number) {
+                ValveStatus::Open => "open",
+                ValveStatus::Close => "closed",
+            };
+            let automation = match valve.automation_status_at(&time) {
+                AutomationStatus::ForceOpen => "ForceOpen",
+                AutomationStatus::ForceClose => "ForceClose",
+                _ => "Scheduled",
+            };
+            let next_run = config
+                .next_run(valve, &time)
+                .map(|next_run| next_run.to_rfc3339())
+                .unwrap_or_default();
+            state.insert(topic("status"), status.to_owned());
+            state.insert(topic("automation"), automation.to_owned());
+            let last_run = valve
+                .last_run
+                .map(|last_run| last_run.to_rfc3339())
+                .unwrap_or_default();
+            state.insert(topic("next_run"), next_run);
+            state.insert(topic("last_run"), last_run);
+            if let Some(discovery_prefix) = &settings.discovery_prefix {
+                state.extend(discovery(
+                    settings,
+                    discovery_prefix,
+                    &controller.id,
+                    &config,
+                    valve,
+                ));
+            }
+        }
+    }
+    state
+}
+
+/// Publishes what changed since `published`, the topics of removed valves
+/// are cleared.
+async fn publish_changes(
+    client: &AsyncClient,
+    published: &mut HashMap<String, String>,
+    state: HashMap<String, String>,
+) -> Result<(), rumqttc::ClientError> {
+    for topic in published.keys().filter(|topic| !state.contains_key(*topic)) {
+        client.publish(topic, QoS::AtLeastOnce, true, "").await?;
+    }
+    published.retain(|topic, _| state.contains_key(topic));
+    for (topic, payload) in state {
+        if published.get(&topic) != Some(&payload) {
+            client
+                .publish(&topic, QoS::AtLeastOnce, true, payload.as_str())
+                .await?;
+            published.insert(topic, payload);
+        }
+    }
+    Ok(())
+}
+
+async fn handle_command(
+    prefix: &str,
+    topic: &str,
+    payload: &[u8],
+    controllers: &Controllers,
+    state_file: &StateFile,
+) -> Result<(), Error> {
+    let payload = String::from_utf8_lossy(payload);
+    let (id, valve_number, command) = parse_command(prefix, topic, &payload)?;
+    let controller = controllers
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or(Error::InvalidControllerId)?;
+    let new_state = match command {
+        Command::Valve(ValveStatus::Open) => AutomationStatus::ForceOpen,
+        Command::Valve(ValveStatus::Close) => AutomationStatus::ForceClose,
+        Command::Automation(status) => status,
+        Command::Run(minutes) => {
+            AutomationStatus::ForceOpenUntil(Utc::now() + chrono::Duration::minutes(minutes.into()))
+        }
+    };
+    change_automation_status(&controller, state_file, valve_number, new_state).await
+}
+
+/// Keeps the retained topics up to date and carries out the commands
+/// received, reconnecting whenever the broker goes away.
+pub async fn run_mqtt(
+    settings: MqttSettings,
+    controllers: Controllers,
+    state_file: Arc<StateFile>,
+) -> ! {
+    let availability = format!("{}/status", settings.prefix);
+    let mut options = MqttOptions::new(&settings.client_id, &settings.host, settings.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        &availability,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    let (client, mut event_loop) = AsyncClient::new(options, 64);
+    let command_topic = format!("{}/+/valves/+/+/set", settings.prefix);
+    // What the broker holds, cleared on every connect so it is published again
+    let mut published = HashMap::new();
+    let mut connected = false;
+    let mut ticks = interval(PUBLISH_INTERVAL);
+    loop {
+        tokio::select! {
+            event = event_loop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    tracing::info!("Connected to the MQTT broker {}", settings.host);
+                    connected = true;
+                    published.clear();
+                    let subscribed = client.subscribe(&command_topic, QoS::AtLeastOnce).await;
+                    let announced = client
+                        .publish(&availability, QoS::AtLeastOnce, true, "online")
+                        .await;
+                    if let Err(e) = subscribed.and(announced) {
+                        tracing::warn!("Failed to set up the MQTT topics: {}", e);
+                    }
+                    ticks.reset_immediately();
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let result = handle_command(
+                        &settings.prefix,
+                        &publish.topic,
+                        &publish.payload,
+                        &controllers,
+                        &state_file,
+                    )
+                    .await;
+                    match result {
+                        Ok(()) => ticks.reset_immediately(),
+                        Err(e) => tracing::warn!("Ignoring MQTT command on {}: {}", publish.topic, e),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if connected {
+                        tracing::warn!("Lost the connection to the MQTT broker: {}", e);
+                    }
+                    connected = false;
+                    sleep(RECONNECT_DELAY).await;
+                }
+            },
+            _ = ticks.tick(), if connected => {
+                let state = current_state(&settings, &controllers).await;
+                if let Err(e) = publish_changes(&client, &mut published, state).await {
+                    tracing::warn!("Failed to publish the valve states: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discovery, parse_command, Command, MqttSettings};
+    use crate::datamodel::{AutomationStatus, ControllerConfig, Valve, ValveStatus};
+    use reqwest::Url;
+
+    #[test]
+    fn test_parse_command() {
+        let command = |topic, payload| parse_command("sprenkler", topic, payload);
+        assert_eq!(
+            command("sprenkler/garten/valves/3/automation/set", "ForceOpen").unwrap(),
+            (
+                "garten".to_owned(),
+                3,
+                Command::Automation(AutomationStatus::ForceOpen)
+            )
+        );
+        // The same JSON as the HTTP API
+        let (_, _, until) = command(
+            "sprenkler/garten/valves/3/automation/set",
+            r#"{"ForceCloseUntil": "2021-09-13T06:00:00Z"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            until,
+            Command::Automation(AutomationStatus::ForceCloseUntil(_))
+        ));
+        assert_eq!(
+            command("sprenkler/garten/valves/3/run/set", " 15 ")
+                .unwrap()
+                .2,
+            Command::Run(15)
+        );
+
+        assert_eq!(
+            command("sprenkler/garten/valves/3/valve/set", "closed")
+                .unwrap()
+                .2,
+            Command::Valve(ValveStatus::Close)
+        );
+        assert!(command("sprenkler/garten/valves/3/valve/set", "on").is_err());
+        assert!(command("sprenkler/garten/valves/3/automation/set", "Sometimes").is_err());
+        assert!(command("sprenkler/garten/valves/x/run/set", "15").is_err());
+        assert!(command("other/garten/valves/3/run/set", "15").is_err());
+        assert!(command("sprenkler/garten/valves/3/status", "open").is_err());
+    }
+
+    #[test]
+    fn test_discovery() {
+        let settings = MqttSettings {
+            host: "localhost".to_owned(),
+            port: 1883,
+            prefix: "sprenkler".to_owned(),
+            client_id: "sprenkler-server-sprenkler".to_owned(),
+            discovery_prefix: Some("homeassistant".to_owned()),
+        };
+        let config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        let valve = Valve::new("Beet vorne", 3);
+        let configs = discovery(&settings, "homeassistant", "mein-garten", &config, &valve);
+        assert_eq!(configs.len(), 4);
+
+        let switch: serde_json::Value = serde_json::from_str(
+            &configs["homeassistant/switch/sprenkler_mein-garten_3/valve/config"],
+        )
+        .unwrap();
+        assert_eq!(switch["unique_id"], "sprenkler_mein-garten_3_valve");
+        assert_eq!(switch["device"]["name"], "Beet vorne");
+        assert_eq!(
+            switch["command_topic"],
+            "sprenkler/mein-garten/valves/3/valve/set"
+        );
+        let select: serde_json::Value = serde_json::from_str(
+            &configs["homeassistant/select/sprenkler_mein-garten_3/automation/config"],
+        )
+        .unwrap();
+        assert_eq!(
+            select["state_topic"],
+            "sprenkler/mein-garten/valves/3/automation"
+        );
+        assert!(
+            configs.contains_key("homeassistant/sensor/sprenkler_mein-garten_3/next_run/config")
+        );
+        assert!(
+            configs.contains_key("homeassistant/sensor/sprenkler_mein-garten_3/last_run/config")
+        );
+    }
+}
@@ -1,42 +1,136 @@
-use chrono::{NaiveTime, Weekday};
+use chrono::{NaiveDate, Weekday};
+use chrono_tz::Tz;
 use handlebars::Handlebars;
 use std::sync::Arc;
 use warp::{Filter, Rejection};
 
-use filters::{detail_view_filter, update_valve_status_filter};
-use serde::{Deserialize, Serialize};
+use filters::{detail_view_filter, update_valve_status_filter, valve_status_filter};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
-use crate::datamodel::{ServerConfig, ValveNumber};
+use crate::datamodel::{Controllers, ScheduleKind, TimeOfDay, ValveNumber, ValveRole};
+use crate::persistence::StateFile;
+
+pub use self::handlers::change_automation_status;
 
 use self::filters::{
-    add_duration_filter, create_valve_filter, delete_duration_filter, delete_valve_filter,
-    homepage_filter,
+    acknowledge_safety_events_filter, add_calendar_entry_filter, add_duration_filter,
+    add_interval_duration_filter, add_program_step_filter, add_sensor_filter,
+    create_controller_filter, create_program_filter, create_valve_filter,
+    delete_calendar_entry_filter, delete_controller_filter, delete_duration_filter,
+    delete_interval_duration_filter, delete_program_filter, delete_program_step_filter,
+    delete_season_filter, delete_sensor_filter, delete_valve_filter, homepage_filter,
+    program_view_filter, record_reading_filter, rename_valve_filter, set_interval_filter,
+    set_limit_filter, set_location_filter, set_max_open_filter, set_moisture_threshold_filter,
+    set_rain_delay_filter, set_rain_skip_filter, set_role_filter, set_schedule_kind_filter,
+    set_season_filter, set_sensor_valves_filter, set_transport_filter, set_valve_max_open_filter,
+    set_valve_water_budget_filter, set_water_budget_filter, update_program_filter,
 };
 
+#[allow(unknown_lints, mismatched_lifetime_syntaxes)]
 pub fn get_dynamic_paths(
     hb: Arc<Handlebars>,
-    config: ServerConfig,
+    controllers: Controllers,
+    state_file: Arc<StateFile>,
+    default_timezone: Tz,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone + '_ {
-    let homepage = homepage_filter(config.clone(), hb.clone());
-
-    let create_valve = create_valve_filter(config.clone());
-    let delete_valve = delete_valve_filter(config.clone());
-
-    let toggle_status = update_valve_status_filter(config.clone());
-
-    let detail_view = detail_view_filter(config.clone(), hb.clone());
+    let homepage = homepage_filter(controllers.clone(), hb.clone());
+    let create_controller =
+        create_controller_filter(controllers.clone(), state_file.clone(), default_timezone);
+    let delete_controller = delete_controller_filter(controllers.clone(), state_file.clone());
+    let set_location = set_location_filter(controllers.clone(), state_file.clone());
+    let set_transport = set_transport_filter(controllers.clone(), state_file.clone());
+    let set_water_budget = set_water_budget_filter(controllers.clone(), state_file.clone());
+    let set_limit = set_limit_filter(controllers.clone(), state_file.clone());
+    let set_valve_water_budget =
+        set_valve_water_budget_filter(controllers.clone(), state_file.clone());
+    let set_role = set_role_filter(controllers.clone(), state_file.clone());
+    let rename_valve = rename_valve_filter(controllers.clone(), state_file.clone());
+    let set_max_open = set_max_open_filter(controllers.clone(), state_file.clone());
+    let set_rain_delay = set_rain_delay_filter(controllers.clone(), state_file.clone());
+    let set_rain_skip = set_rain_skip_filter(controllers.clone(), state_file.clone());
+    let set_moisture_threshold =
+        set_moisture_threshold_filter(controllers.clone(), state_file.clone());
+    let add_sensor = add_sensor_filter(controllers.clone(), state_file.clone());
+    let delete_sensor = delete_sensor_filter(controllers.clone(), state_file.clone());
+    let set_sensor_valves = set_sensor_valves_filter(controllers.clone(), state_file.clone());
+    let record_reading = record_reading_filter(controllers.clone(), state_file.clone());
+    let set_valve_max_open = set_valve_max_open_filter(controllers.clone(), state_file.clone());
+    let acknowledge_safety_events =
+        acknowledge_safety_events_filter(controllers.clone(), state_file.clone());
+    let create_program = create_program_filter(controllers.clone(), state_file.clone());
+    let program_view = program_view_filter(controllers.clone(), hb.clone());
+    let delete_program = delete_program_filter(controllers.clone(), state_file.clone());
+    let update_program = update_program_filter(controllers.clone(), state_file.clone());
+    let add_program_step = add_program_step_filter(controllers.clone(), state_file.clone());
+    let delete_program_step = delete_program_step_filter(controllers.clone(), state_file.clone());
+    let create_valve = create_valve_filter(controllers.clone(), state_file.clone());
+    let delete_valve = delete_valve_filter(controllers.clone(), state_file.clone());
+    let toggle_status = update_valve_status_filter(controllers.clone(), state_file.clone());
+    let valve_status = valve_status_filter(controllers.clone());
+    let detail_view = detail_view_filter(controllers.clone(), hb.clone());
+    let add_duration = add_duration_filter(controllers.clone(), state_file.clone());
+    let delete_duration = delete_duration_filter(controllers.clone(), state_file.clone());
+    let add_calendar_entry = add_calendar_entry_filter(controllers.clone(), state_file.clone());
+    let delete_calendar_entry =
+        delete_calendar_entry_filter(controllers.clone(), state_file.clone());
+    let set_season = set_season_filter(controllers.clone(), state_file.clone());
+    let delete_season = delete_season_filter(controllers.clone(), state_file.clone());
+    let set_schedule_kind = set_schedule_kind_filter(controllers.clone(), state_file.clone());
+    let set_interval = set_interval_filter(controllers.clone(), state_file.clone());
+    let add_interval_duration =
+        add_interval_duration_filter(controllers.clone(), state_file.clone());
+    let delete_interval_duration = delete_interval_duration_filter(controllers, state_file);
 
-    let add_duration = add_duration_filter(config.clone());
-    let delete_duration = delete_duration_filter(config);
-
-    homepage.or(warp::path("valves").and(
-        detail_view
+    homepage
+        .or(create_controller)
+        .or(delete_controller)
+        .or(set_location)
+        .or(set_transport)
+        .or(set_water_budget)
+        .or(set_limit)
+        .or(set_max_open)
+        .or(acknowledge_safety_events)
+        .or(set_rain_delay)
+        .or(set_rain_skip)
+        .or(add_sensor
+            .or(delete_sensor)
+            .or(set_sensor_valves)
+            .or(record_reading))
+        .or(create_program
+            .or(program_view)
+            .or(delete_program)
+            .or(update_program)
+            .or(add_program_step)
+            .or(delete_program_step))
+        .or(detail_view
             .or(toggle_status)
+            .or(valve_status)
             .or(create_valve)
             .or(delete_valve)
             .or(add_duration)
-            .or(delete_duration),
-    ))
+            .or(delete_duration)
+            .or(add_calendar_entry)
+            .or(delete_calendar_entry)
+            .or(set_season)
+            .or(delete_season)
+            .or(set_schedule_kind)
+            .or(set_interval)
+            .or(add_interval_duration)
+            .or(delete_interval_duration)
+            .or(set_valve_water_budget)
+            .or(set_role)
+            .or(rename_valve)
+            .or(set_valve_max_open)
+            .or(set_moisture_threshold))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControllerParams {
+    pub id: String,
+    pub name: String,
+    pub address: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,268 +139,2181 @@ pub struct ValveParams {
     pub name: String,
 }
 
+/// Empty form fields mean the value wasn't given.
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) if !s.trim().is_empty() => s.trim().parse().map(Some).map_err(de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+/// Either `end_time` or `run_minutes` is required, the end time wins if both are set.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TimetableParams {
-    pub start_time: NaiveTime,
-    pub end_time: NaiveTime,
+    pub start_time: TimeOfDay,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub end_time: Option<TimeOfDay>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub run_minutes: Option<u32>,
     pub day: Weekday,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScheduleKindParams {
+    pub kind: ScheduleKind,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntervalParams {
+    pub every_days: u16,
+    pub anchor: NaiveDate,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntervalTimetableParams {
+    pub start_time: TimeOfDay,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub end_time: Option<TimeOfDay>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub run_minutes: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameParams {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoleParams {
+    pub role: ValveRole,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LimitParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub max_concurrent_open: Option<usize>,
+}
+
+/// An empty `hours` cancels the rain delay.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RainDelayParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub hours: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SensorParams {
+    pub id: String,
+    pub name: String,
+}
+
+/// Comma separated valve numbers.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SensorValvesParams {
+    pub valves: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadingParams {
+    pub moisture: f64,
+}
+
+/// An empty `moisture_threshold` always waters.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MoistureThresholdParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub moisture_threshold: Option<f64>,
+}
+
+/// An empty `rain_skip_mm` never skips a day.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RainSkipParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub rain_skip_mm: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MaxOpenParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub max_open_minutes: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum TransportKind {
+    Http,
+    Mqtt,
+    Simulated,
+}
+
+/// Broker and topic prefix are only needed for MQTT, an empty port is 1883.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransportParams {
+    pub kind: TransportKind,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub topic_prefix: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WaterBudgetParams {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub water_budget: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LocationParams {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Weekdays are checkboxes named after the day.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProgramParams {
+    pub name: String,
+    pub start: TimeOfDay,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub pause_minutes: Option<u32>,
+    #[serde(default, rename = "Mon")]
+    pub monday: bool,
+    #[serde(default, rename = "Tue")]
+    pub tuesday: bool,
+    #[serde(default, rename = "Wed")]
+    pub wednesday: bool,
+    #[serde(default, rename = "Thu")]
+    pub thursday: bool,
+    #[serde(default, rename = "Fri")]
+    pub friday: bool,
+    #[serde(default, rename = "Sat")]
+    pub saturday: bool,
+    #[serde(default, rename = "Sun")]
+    pub sunday: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProgramStepParams {
+    pub valve_number: ValveNumber,
+    pub run_minutes: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProgramStepIndexParams {
+    pub index: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SeasonParams {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub yearly: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CalendarEntryKind {
+    /// Don't run the weekly entries on the date
+    Skip,
+    /// Run the given times instead of the weekly entries
+    Replace,
+    /// Run the given times in addition to the planned entries
+    Extra,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CalendarParams {
+    pub date: NaiveDate,
+    pub kind: CalendarEntryKind,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub start_time: Option<TimeOfDay>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub end_time: Option<TimeOfDay>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub run_minutes: Option<u32>,
+}
 mod filters {
     use super::handlers::{
-        add_duration, create_valve, delete_duration, delete_valve, render_details, render_homepage,
-        update_valve_status,
+        acknowledge_safety_events, add_calendar_entry, add_duration, add_interval_duration,
+        add_program_step, add_sensor, create_controller, create_program, create_valve,
+        delete_calendar_entry, delete_controller, delete_duration, delete_interval_duration,
+        delete_program, delete_program_step, delete_season, delete_sensor, delete_valve,
+        record_reading, rename_valve, render_details, render_homepage, render_program,
+        set_interval, set_limit, set_location, set_max_open, set_moisture_threshold,
+        set_rain_delay, set_rain_skip, set_role, set_schedule_kind, set_season, set_sensor_valves,
+        set_transport, set_valve_max_open, set_valve_water_budget, set_water_budget,
+        update_program, update_valve_status, valve_status,
     };
-    use crate::{datamodel::ServerConfig, hb::render};
+    use crate::datamodel::{Controller, ControllerId, Controllers};
+    use crate::{hb::render, persistence::StateFile};
+    use chrono_tz::Tz;
     use handlebars::Handlebars;
 
     use std::sync::Arc;
     use warp::Filter;
 
     /// GET /
+    #[allow(unknown_lints, mismatched_lifetime_syntaxes)]
     pub fn homepage_filter(
-        config: ServerConfig,
+        controllers: Controllers,
         hb: Arc<Handlebars>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + '_ {
         let render = move |t| render(t, hb.clone());
         warp::get()
             .and(warp::path::end())
-            .and(with_server_config(config))
+            .and(with_controllers(controllers))
             .and_then(render_homepage)
             .and_then(render.clone())
     }
 
-    /// POST /
+    /// POST /controllers
+    pub fn create_controller_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+        default_timezone: Tz,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(warp::path("controllers"))
+            .and(warp::path::end())
+            .and(with_controllers(controllers))
+            .and(with_state_file(state_file))
+            .and(warp::any().map(move || default_timezone))
+            .and(warp::body::form())
+            .and_then(create_controller)
+    }
+
+    /// DELETE /controllers/:cid
+    pub fn delete_controller_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::delete()
+            .and(warp::path("controllers"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_controllers(controllers))
+            .and(with_state_file(state_file))
+            .and_then(delete_controller)
+    }
+
+    /// POST /controllers/:cid/location
+    pub fn set_location_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("location"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_location)
+    }
+
+    /// POST /controllers/:cid/transport
+    pub fn set_transport_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("transport"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_transport)
+    }
+
+    /// POST /controllers/:cid/budget
+    pub fn set_water_budget_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("budget"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_water_budget)
+    }
+
+    /// POST /controllers/:cid/limit
+    pub fn set_limit_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("limit"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_limit)
+    }
+
+    /// POST /controllers/:cid/max_open
+    pub fn set_max_open_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("max_open"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_max_open)
+    }
+
+    /// POST /controllers/:cid/rain_delay
+    pub fn set_rain_delay_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("rain_delay"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_rain_delay)
+    }
+
+    /// POST /controllers/:cid/weather
+    pub fn set_rain_skip_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("weather"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_rain_skip)
+    }
+
+    /// POST /controllers/:cid/safety/acknowledge
+    pub fn acknowledge_safety_events_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("safety"))
+            .and(warp::path("acknowledge"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and_then(acknowledge_safety_events)
+    }
+
+    /// POST /controllers/:cid/valves
     pub fn create_valve_filter(
-        config: ServerConfig,
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
             .and(warp::path::end())
             .and(warp::body::form())
-            .and(with_server_config(config))
+            .and(with_state_file(state_file))
             .and_then(create_valve)
     }
-    /// GET /:id/
+    /// GET /controllers/:cid/valves/:id/
+    #[allow(unknown_lints, mismatched_lifetime_syntaxes)]
     pub fn detail_view_filter(
-        config: ServerConfig,
+        controllers: Controllers,
         hb: Arc<Handlebars>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + '_ {
         let render = move |t| render(t, hb.clone());
         warp::get()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
             .and(warp::path::param())
             .and(warp::path::end())
-            .and(with_server_config(config))
             .and_then(render_details)
             .and_then(render.clone())
     }
 
-    /// DELETE /:id/
+    /// DELETE /controllers/:cid/valves/:id/
     pub fn delete_valve_filter(
-        config: ServerConfig,
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
             .and(warp::path::param())
             .and(warp::path::end())
-            .and(with_server_config(config))
+            .and(with_state_file(state_file))
             .and_then(delete_valve)
     }
-    /// POST /:id/status
+    /// POST /controllers/:cid/valves/:id/status
     pub fn update_valve_status_filter(
-        config: ServerConfig,
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
             .and(warp::path::param())
             .and(warp::path("status"))
-            .and(with_server_config(config))
+            .and(with_state_file(state_file))
             .and(warp::body::json())
             .and_then(update_valve_status)
     }
 
-    /// POST /:id/timetable
+    /// GET /controllers/:cid/valves/:id/status
+    pub fn valve_status_filter(
+        controllers: Controllers,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::get()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("status"))
+            .and(warp::path::end())
+            .and_then(valve_status)
+    }
+
+    /// POST /controllers/:cid/valves/:id/timetable
     pub fn add_duration_filter(
-        config: ServerConfig,
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
             .and(warp::path::param())
             .and(warp::path("timetable"))
-            .and(with_server_config(config))
+            .and(with_state_file(state_file))
             .and(warp::body::form())
             .and_then(add_duration)
     }
-    /// DELETE /:id/timetable
+    /// DELETE /controllers/:cid/valves/:id/timetable
     pub fn delete_duration_filter(
-        config: ServerConfig,
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
     ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
         warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
             .and(warp::path::param())
             .and(warp::path("timetable"))
-            .and(with_server_config(config))
+            .and(with_state_file(state_file))
             .and(warp::body::json())
             .and_then(delete_duration)
     }
 
-    pub fn with_server_config(
-        config: ServerConfig,
-    ) -> impl Filter<Extract = (ServerConfig,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || config.clone())
+    /// POST /controllers/:cid/valves/:id/calendar
+    pub fn add_calendar_entry_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("calendar"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(add_calendar_entry)
+    }
+    /// DELETE /controllers/:cid/valves/:id/calendar
+    pub fn delete_calendar_entry_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("calendar"))
+            .and(with_state_file(state_file))
+            .and(warp::body::json())
+            .and_then(delete_calendar_entry)
     }
-}
 
-mod handlers {
-    use crate::datamodel::{
-        AutomationStatus, ControllerConfig, Duration, Error::InvalidValveNumber, Schedule,
-        ServerConfig, Valve, ValveNumber, ValveStatus,
-    };
+    /// POST /controllers/:cid/valves/:id/kind
+    pub fn set_schedule_kind_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("kind"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_schedule_kind)
+    }
 
-    use chrono::{Local, NaiveDateTime};
-    use hyper::Uri;
-    use reqwest::Url;
+    /// POST /controllers/:cid/valves/:id/interval
+    pub fn set_interval_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("interval"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_interval)
+    }
 
-    use std::convert::{Infallible, TryFrom};
-    use warp::http::StatusCode;
+    /// POST /controllers/:cid/valves/:id/interval/timetable
+    pub fn add_interval_duration_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("interval"))
+            .and(warp::path("timetable"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(add_interval_duration)
+    }
+    /// DELETE /controllers/:cid/valves/:id/interval/timetable
+    pub fn delete_interval_duration_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("interval"))
+            .and(warp::path("timetable"))
+            .and(with_state_file(state_file))
+            .and(warp::body::json())
+            .and_then(delete_interval_duration)
+    }
 
-    use crate::hb::WithTemplate;
+    /// POST /controllers/:cid/valves/:id/season
+    pub fn set_season_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("season"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_season)
+    }
+    /// DELETE /controllers/:cid/valves/:id/season
+    pub fn delete_season_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("season"))
+            .and(with_state_file(state_file))
+            .and_then(delete_season)
+    }
 
-    use serde::Serialize;
-    use serde_json::json;
+    /// POST /controllers/:cid/valves/:id/budget
+    pub fn set_valve_water_budget_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("budget"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_valve_water_budget)
+    }
 
-    use super::{TimetableParams, ValveParams};
+    /// POST /controllers/:cid/valves/:id/max_open
+    pub fn set_valve_max_open_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("max_open"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_valve_max_open)
+    }
 
-    #[derive(Serialize, Debug)]
-    pub struct ValveData<'a> {
-        name: &'a str,
-        valve_number: ValveNumber,
-        automation_status: AutomationStatus,
-        schedule: &'a Schedule,
-        valve_status: ValveStatus,
+    /// POST /controllers/:cid/valves/:id/moisture
+    pub fn set_moisture_threshold_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("moisture"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_moisture_threshold)
     }
 
-    impl<'a> ValveData<'a> {
-        pub fn from(valve: &'a Valve, time: NaiveDateTime) -> ValveData<'a> {
-            ValveData {
-                name: &valve.name,
-                valve_number: valve.valve_number,
-                automation_status: valve.automation_status.clone(),
-                schedule: valve.schedule(),
-                valve_status: valve.valve_status(time),
-            }
-        }
+    /// POST /controllers/:cid/sensors
+    pub fn add_sensor_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("sensors"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(add_sensor)
     }
-    #[derive(Serialize, Debug)]
-    struct HomepageData<'a> {
-        valves: Vec<ValveData<'a>>,
-        address: &'a Url,
+
+    /// DELETE /controllers/:cid/sensors/:id
+    pub fn delete_sensor_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("sensors"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and_then(delete_sensor)
     }
 
-    impl<'a> HomepageData<'a> {
-        pub fn from(config: &'a ControllerConfig, time: NaiveDateTime) -> HomepageData<'a> {
-            HomepageData {
-                valves: config
-                    .iter()
-                    .map(|valve| ValveData::from(valve, time))
-                    .collect(),
-                address: &config.address,
-            }
-        }
+    /// POST /controllers/:cid/sensors/:id/valves
+    pub fn set_sensor_valves_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("sensors"))
+            .and(warp::path::param())
+            .and(warp::path("valves"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_sensor_valves)
+    }
+
+    /// POST /controllers/:cid/sensors/:id/reading
+    pub fn record_reading_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("sensors"))
+            .and(warp::path::param())
+            .and(warp::path("reading"))
+            .and(with_state_file(state_file))
+            .and(warp::body::json())
+            .and_then(record_reading)
+    }
+
+    /// POST /controllers/:cid/programs
+    pub fn create_program_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("programs"))
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(create_program)
+    }
+
+    /// GET /controllers/:cid/programs/:id
+    pub fn program_view_filter(
+        controllers: Controllers,
+        hb: Arc<Handlebars<'_>>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + '_ {
+        let render = move |t| render(t, hb.clone());
+        warp::get()
+            .and(with_controller(controllers))
+            .and(warp::path("programs"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and_then(render_program)
+            .and_then(render.clone())
+    }
+
+    /// DELETE /controllers/:cid/programs/:id
+    pub fn delete_program_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("programs"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(with_state_file(state_file))
+            .and_then(delete_program)
+    }
+
+    /// POST /controllers/:cid/programs/:id/settings
+    pub fn update_program_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("programs"))
+            .and(warp::path::param())
+            .and(warp::path("settings"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(update_program)
+    }
+
+    /// POST /controllers/:cid/programs/:id/steps
+    pub fn add_program_step_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("programs"))
+            .and(warp::path::param())
+            .and(warp::path("steps"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(add_program_step)
+    }
+    /// DELETE /controllers/:cid/programs/:id/steps
+    pub fn delete_program_step_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::delete()
+            .and(with_controller(controllers))
+            .and(warp::path("programs"))
+            .and(warp::path::param())
+            .and(warp::path("steps"))
+            .and(with_state_file(state_file))
+            .and(warp::body::json())
+            .and_then(delete_program_step)
+    }
+
+    /// POST /controllers/:cid/valves/:id/role
+    pub fn set_role_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("role"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(set_role)
+    }
+
+    /// POST /controllers/:cid/valves/:id/name
+    pub fn rename_valve_filter(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+        warp::post()
+            .and(with_controller(controllers))
+            .and(warp::path("valves"))
+            .and(warp::path::param())
+            .and(warp::path("name"))
+            .and(with_state_file(state_file))
+            .and(warp::body::form())
+            .and_then(rename_valve)
+    }
+
+    pub fn with_controllers(
+        controllers: Controllers,
+    ) -> impl Filter<Extract = (Controllers,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || controllers.clone())
+    }
+
+    /// Matches `/controllers/:cid` and extracts that controller, unknown ids
+    /// aren't found.
+    pub fn with_controller(
+        controllers: Controllers,
+    ) -> impl Filter<Extract = (Controller,), Error = warp::Rejection> + Clone {
+        warp::path("controllers")
+            .and(warp::path::param())
+            .and_then(move |id: ControllerId| {
+                let controllers = controllers.clone();
+                async move {
+                    controllers
+                        .read()
+                        .await
+                        .get(&id)
+                        .cloned()
+                        .ok_or_else(warp::reject::not_found)
+                }
+            })
+    }
+
+    pub fn with_state_file(
+        state_file: Arc<StateFile>,
+    ) -> impl Filter<Extract = (Arc<StateFile>,), Error = std::convert::Infallible> + Clone {
+        warp::any().map(move || state_file.clone())
+    }
+}
+
+mod handlers {
+    use crate::datamodel::{
+        check_controller_id, AutomationStatus, Calendar, ConcurrencyConflict, Controller,
+        ControllerConfig, ControllerId, Controllers, Duration, Error, Error::InvalidValveNumber,
+        IntervalSchedule, Location, Plan, Program, ProgramId, ProgramStep, Schedule, ScheduleKind,
+        Season, Sensor, SensorId, SkipReason, TimeOfDay, Valve, ValveHealth, ValveNumber,
+        ValveRole, ValveStatus,
+    };
+
+    use chrono::{DateTime, Weekday};
+    use chrono_tz::Tz;
+    use hyper::Uri;
+    use reqwest::Url;
+
+    use std::convert::{Infallible, TryFrom};
+    use std::sync::Arc;
+    use warp::http::StatusCode;
+
+    use crate::executor::control_valves;
+    use crate::hb::WithTemplate;
+    use crate::persistence::StateFile;
+    use crate::transport::TransportConfig;
+
+    use serde::Serialize;
+    use serde_json::json;
+
+    use super::{
+        CalendarEntryKind, CalendarParams, ControllerParams, IntervalParams,
+        IntervalTimetableParams, LimitParams, LocationParams, MaxOpenParams,
+        MoistureThresholdParams, ProgramParams, ProgramStepIndexParams, ProgramStepParams,
+        RainDelayParams, RainSkipParams, ReadingParams, RenameParams, RoleParams,
+        ScheduleKindParams, SeasonParams, SensorParams, SensorValvesParams, TimetableParams,
+        TransportKind, TransportParams, ValveParams, WaterBudgetParams,
+    };
+
+    #[derive(Serialize, Debug)]
+    pub struct ValveData<'a> {
+        /// Id of the controller the valve belongs to.
+        controller: &'a str,
+        name: &'a str,
+        valve_number: ValveNumber,
+        /// The mode in effect, a timed override shows as forced.
+        automation_status: AutomationStatus,
+        /// End of a timed override as local wall clock time.
+        override_until: Option<String>,
+        role: ValveRole,
+        schedule: &'a Schedule,
+        schedule_kind: ScheduleKind,
+        interval_schedule: &'a IntervalSchedule,
+        calendar: &'a Calendar,
+        season: Option<&'a Season>,
+        in_season: bool,
+        water_budget: Option<u32>,
+        effective_water_budget: u32,
+        max_open_minutes: Option<u32>,
+        moisture_threshold: Option<f64>,
+        last_skip: Option<SkipData>,
+        /// When the valve last opened, local wall clock time.
+        last_run: Option<String>,
+        valve_status: ValveStatus,
+        /// Waiting for a free slot under `max_concurrent_open`.
+        queued: bool,
+        reported_status: Option<ValveStatus>,
+        drift: bool,
+        health: &'a ValveHealth,
+    }
+
+    impl<'a> ValveData<'a> {
+        pub fn from(
+            controller: &'a str,
+            valve: &'a Valve,
+            time: &DateTime<Tz>,
+            config: &ControllerConfig,
+            plan: &Plan,
+        ) -> ValveData<'a> {
+            let valve_status = plan.status(valve.valve_number);
+            ValveData {
+                controller,
+                name: &valve.name,
+                valve_number: valve.valve_number,
+                automation_status: valve.automation_status_at(time),
+                override_until: valve.automation_status().until().map(|until| {
+                    until
+                        .with_timezone(&time.timezone())
+                        .format("%H:%M")
+                        .to_string()
+                }),
+                role: valve.role,
+                schedule: valve.schedule(),
+                schedule_kind: valve.schedule_kind,
+                interval_schedule: valve.interval_schedule(),
+                calendar: valve.calendar(),
+                season: valve.season.as_ref(),
+                in_season: valve.in_season(&time.naive_local().date()),
+                water_budget: valve.water_budget(),
+                effective_water_budget: valve.scaled(&config.environment()).water_budget,
+                max_open_minutes: valve.max_open_minutes(),
+                moisture_threshold: valve.moisture_threshold(),
+                last_skip: valve.last_skip.as_ref().map(|skip| {
+                    let (sensor, moisture) = match &skip.reason {
+                        SkipReason::SoilWet { sensor, moisture } => {
+                            (Some(sensor.clone()), Some(*moisture))
+                        }
+                        _ => (None, None),
+                    };
+                    SkipData {
+                        begin: skip
+                            .begin
+                            .with_timezone(&time.timezone())
+                            .format("%d.%m. %H:%M")
+                            .to_string(),
+                        reason: match skip.reason {
+                            SkipReason::RainDelay => "RainDelay",
+                            SkipReason::Weather => "Weather",
+                            SkipReason::SoilWet { .. } => "SoilWet",
+                        },
+                        sensor,
+                        moisture,
+                    }
+                }),
+                last_run: valve.last_run.map(|last_run| {
+                    last_run
+                        .with_timezone(&time.timezone())
+                        .format("%d.%m. %H:%M")
+                        .to_string()
+                }),
+                valve_status,
+                queued: plan.is_queued(valve.valve_number),
+                reported_status: valve.reported_status.clone(),
+                drift: valve.drifted(),
+                health: &valve.health,
+            }
+        }
+    }
+    #[derive(Serialize, Debug)]
+    struct SkipData {
+        begin: String,
+        reason: &'static str,
+        sensor: Option<SensorId>,
+        moisture: Option<f64>,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct SensorData<'a> {
+        id: &'a str,
+        name: &'a str,
+        valves: String,
+        moisture: Option<f64>,
+        read_at: Option<String>,
+        /// The reading is recent enough to count.
+        fresh: bool,
+    }
+
+    impl<'a> SensorData<'a> {
+        fn from(sensor: &'a Sensor, time: &DateTime<Tz>) -> SensorData<'a> {
+            SensorData {
+                id: &sensor.id,
+                name: &sensor.name,
+                valves: sensor
+                    .valves()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                moisture: sensor.moisture(),
+                read_at: sensor.read_at().map(|read_at| {
+                    read_at
+                        .with_timezone(&time.timezone())
+                        .format("%d.%m. %H:%M")
+                        .to_string()
+                }),
+                fresh: sensor.is_fresh(time),
+            }
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    struct ProgramStepData<'a> {
+        index: usize,
+        valve_number: ValveNumber,
+        valve_name: Option<&'a str>,
+        run_minutes: u32,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct ProgramRunData {
+        valve_number: ValveNumber,
+        begin: String,
+        end: String,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct ProgramDayData {
+        day: Weekday,
+        active: bool,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct ValveNameData<'a> {
+        valve_number: ValveNumber,
+        name: &'a str,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct ProgramData<'a> {
+        controller: &'a str,
+        id: ProgramId,
+        name: &'a str,
+        start: TimeOfDay,
+        pause_minutes: u32,
+        weekdays: &'a [Weekday],
+        days: Vec<ProgramDayData>,
+        steps: Vec<ProgramStepData<'a>>,
+        /// When the steps run if the program is started today.
+        today: Vec<ProgramRunData>,
+        valves: Vec<ValveNameData<'a>>,
+    }
+
+    impl<'a> ProgramData<'a> {
+        fn from(
+            controller: &'a str,
+            program: &'a Program,
+            config: &'a ControllerConfig,
+        ) -> ProgramData<'a> {
+            let today = config.now().naive_local().date();
+            let runs = config.program_runs(program, today);
+            ProgramData {
+                controller,
+                id: program.id,
+                name: &program.name,
+                start: program.start,
+                pause_minutes: program.pause_minutes,
+                weekdays: program.weekdays(),
+                days: std::iter::successors(Some(Weekday::Mon), |day| Some(day.succ()))
+                    .take(7)
+                    .map(|day| ProgramDayData {
+                        day,
+                        active: program.weekdays().contains(&day),
+                    })
+                    .collect(),
+                steps: program
+                    .steps()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, step)| ProgramStepData {
+                        index,
+                        valve_number: step.valve_number,
+                        valve_name: config.get(step.valve_number).map(|v| v.name.as_str()),
+                        run_minutes: step.run_minutes,
+                    })
+                    .collect(),
+                today: runs
+                    .into_iter()
+                    .map(|run| ProgramRunData {
+                        valve_number: run.valve_number,
+                        begin: run.begin.format("%H:%M").to_string(),
+                        end: run.end.format("%H:%M").to_string(),
+                    })
+                    .collect(),
+                valves: config
+                    .iter()
+                    .filter(|valve| valve.is_zone())
+                    .map(|valve| ValveNameData {
+                        valve_number: valve.valve_number,
+                        name: &valve.name,
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    struct HomepageData<'a> {
+        id: &'a str,
+        name: &'a str,
+        valves: Vec<ValveData<'a>>,
+        queue: Vec<ValveNumber>,
+        max_concurrent_open: Option<usize>,
+        conflicts: Vec<ConcurrencyConflict>,
+        programs: Vec<ProgramData<'a>>,
+        address: &'a Url,
+        transport: TransportData<'a>,
+        location: Option<&'a Location>,
+        water_budget: u32,
+        max_open_minutes: Option<u32>,
+        /// Safety events that weren't acknowledged yet.
+        safety_events: Vec<SafetyEventData<'a>>,
+        rain_delay: Option<RainDelayData>,
+        weather: Option<WeatherData>,
+        rain_skip_mm: Option<f64>,
+        sensors: Vec<SensorData<'a>>,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct WeatherData {
+        time: String,
+        rain_mm: f64,
+        max_temperature: f64,
+        percent: u32,
+        skip: bool,
+        /// The decision is for today and applies.
+        today: bool,
+    }
+
+    /// The fields of the other kinds are `None`.
+    #[derive(Serialize, Debug)]
+    struct TransportData<'a> {
+        kind: &'static str,
+        host: Option<&'a str>,
+        port: Option<u16>,
+        topic_prefix: Option<&'a str>,
+    }
+
+    impl<'a> TransportData<'a> {
+        fn from(transport: &'a TransportConfig) -> TransportData<'a> {
+            let kind = match transport {
+                TransportConfig::Http => "Http",
+                TransportConfig::Mqtt { .. } => "Mqtt",
+                TransportConfig::Simulated => "Simulated",
+            };
+            match transport {
+                TransportConfig::Mqtt {
+                    host,
+                    port,
+                    topic_prefix,
+                } => TransportData {
+                    kind,
+                    host: Some(host),
+                    port: Some(*port),
+                    topic_prefix: Some(topic_prefix),
+                },
+                _ => TransportData {
+                    kind,
+                    host: None,
+                    port: None,
+                    topic_prefix: None,
+                },
+            }
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    struct RainDelayData {
+        until: String,
+        remaining_hours: i64,
+        remaining_minutes: i64,
+    }
+
+    #[derive(Serialize, Debug)]
+    struct SafetyEventData<'a> {
+        valve_number: ValveNumber,
+        valve_name: Option<&'a str>,
+        time: String,
+        open_minutes: i64,
+    }
+
+    impl<'a> HomepageData<'a> {
+        pub fn from(
+            id: &'a str,
+            config: &'a ControllerConfig,
+            time: &DateTime<Tz>,
+        ) -> HomepageData<'a> {
+            let plan = config.plan(time);
+            HomepageData {
+                id,
+                name: &config.name,
+                valves: config
+                    .iter()
+                    .map(|valve| ValveData::from(id, valve, time, config, &plan))
+                    .collect(),
+                queue: plan.queue.clone(),
+                max_concurrent_open: config.max_concurrent_open(),
+                conflicts: config.concurrency_conflicts(time),
+                programs: config
+                    .programs()
+                    .map(|program| ProgramData::from(id, program, config))
+                    .collect(),
+                address: &config.address,
+                transport: TransportData::from(config.transport()),
+                location: config.location.as_ref(),
+                water_budget: config.water_budget(),
+                max_open_minutes: config.max_open_minutes(),
+                safety_events: config
+                    .safety_events()
+                    .iter()
+                    .filter(|event| !event.acknowledged)
+                    .map(|event| SafetyEventData {
+                        valve_number: event.valve_number,
+                        valve_name: config
+                            .get(event.valve_number)
+                            .map(|valve| valve.name.as_str()),
+                        time: event
+                            .time
+                            .with_timezone(&time.timezone())
+                            .format("%d.%m. %H:%M")
+                            .to_string(),
+                        open_minutes: event.open_minutes,
+                    })
+                    .collect(),
+                rain_delay: config.rain_delay_until(time).map(|until| {
+                    let remaining = until.signed_duration_since(*time);
+                    RainDelayData {
+                        until: until
+                            .with_timezone(&time.timezone())
+                            .format("%d.%m. %H:%M")
+                            .to_string(),
+                        remaining_hours: remaining.num_hours(),
+                        remaining_minutes: remaining.num_minutes() % 60,
+                    }
+                }),
+                weather: config.weather().map(|decision| WeatherData {
+                    time: decision
+                        .time
+                        .with_timezone(&time.timezone())
+                        .format("%d.%m. %H:%M")
+                        .to_string(),
+                    rain_mm: decision.report.rain_mm,
+                    max_temperature: decision.report.max_temperature,
+                    percent: decision.adjustment.percent,
+                    skip: decision.adjustment.skip,
+                    today: decision.adjustment.date == time.naive_local().date(),
+                }),
+                rain_skip_mm: config.rain_skip_mm(),
+                sensors: config
+                    .sensors()
+                    .iter()
+                    .map(|sensor| SensorData::from(sensor, time))
+                    .collect(),
+            }
+        }
     }
 
     pub async fn update_valve_status(
+        controller: Controller,
         valve_number: ValveNumber,
-        config: ServerConfig,
+        state_file: Arc<StateFile>,
         new_state: AutomationStatus,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        let mut controller_config = config.write().await;
-        let v = &mut controller_config
-            .get_mut(valve_number)
-            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?;
-        v.automation_status = new_state.clone();
+        change_automation_status(&controller, &state_file, valve_number, new_state).await?;
         Ok(StatusCode::OK)
     }
 
+    /// Sets the mode of a valve, also used for the commands received over MQTT.
+    pub async fn change_automation_status(
+        controller: &Controller,
+        state_file: &StateFile,
+        valve_number: ValveNumber,
+        new_state: AutomationStatus,
+    ) -> Result<(), Error> {
+        let mut controller_config = controller.config.write().await;
+        let now = controller_config.now();
+        controller_config
+            .get_mut(valve_number)
+            .ok_or(InvalidValveNumber)?
+            .set_automation_status(new_state, &now)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut controller_config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(())
+    }
+
     pub async fn render_details(
+        controller: Controller,
         valve_number: ValveNumber,
-        config: ServerConfig,
     ) -> Result<WithTemplate<serde_json::Value>, warp::Rejection> {
-        let controller_config = config.read().await;
+        let controller_config = controller.config.read().await;
+        let time = controller_config.now();
+        let plan = controller_config.plan(&time);
         let valve = &controller_config.get(valve_number);
         valve
             .map(|valve| WithTemplate {
                 name: "timetable",
-                value: json!(ValveData::from(valve, Local::now().naive_local())),
+                value: json!(ValveData::from(
+                    &controller.id,
+                    valve,
+                    &time,
+                    &controller_config,
+                    &plan
+                )),
             })
             .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))
     }
 
+    pub async fn valve_status(
+        controller: Controller,
+        valve_number: ValveNumber,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let controller_config = controller.config.read().await;
+        let valve = controller_config
+            .get(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?;
+        let time = controller_config.now();
+        let plan = controller_config.plan(&time);
+        Ok(warp::reply::json(&ValveData::from(
+            &controller.id,
+            valve,
+            &time,
+            &controller_config,
+            &plan,
+        )))
+    }
+
     pub async fn create_valve(
+        controller: Controller,
         params: ValveParams,
-        config: ServerConfig,
+        state_file: Arc<StateFile>,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        let mut controller_config = config.write().await;
+        let mut controller_config = controller.config.write().await;
         if controller_config.get(params.valve_number).is_some() {
             return Err(warp::reject::custom(InvalidValveNumber {}));
         }
         controller_config.push(Valve::new(params.name, params.valve_number));
+        state_file
+            .save_or_roll_back(&controller.id, &mut controller_config)
+            .await?;
+        controller.wakeup.notify_one();
         Ok(warp::redirect(Uri::from_static("/")))
     }
 
     pub async fn render_homepage(
-        config: ServerConfig,
+        controllers: Controllers,
     ) -> Result<WithTemplate<serde_json::Value>, Infallible> {
-        let controller_config = config.read().await;
-        let controller_config = &(*controller_config);
+        let controllers: Vec<Controller> = controllers.read().await.values().cloned().collect();
+        let mut configs = Vec::with_capacity(controllers.len());
+        for controller in &controllers {
+            configs.push(controller.config.read().await);
+        }
+        let data: Vec<HomepageData> = controllers
+            .iter()
+            .zip(&configs)
+            .map(|(controller, config)| HomepageData::from(&controller.id, config, &config.now()))
+            .collect();
 
         Ok(WithTemplate {
             name: "index",
-            value: json!(HomepageData::from(
-                controller_config,
-                Local::now().naive_local()
-            )),
+            value: json!({ "controllers": data }),
         })
     }
 
+    pub async fn create_controller(
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+        default_timezone: Tz,
+        params: ControllerParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let id = params.id.trim();
+        check_controller_id(id)?;
+        let address = Url::parse(&params.address).map_err(Error::InvalidAddress)?;
+        let mut config = ControllerConfig::new(address);
+        config.timezone = default_timezone;
+        config.name = params.name;
+        let mut registry = controllers.write().await;
+        if registry.contains_key(id) {
+            return Err(warp::reject::custom(Error::InvalidControllerId));
+        }
+        state_file.create(id, &config).await?;
+        registry.insert(id.to_owned(), Controller::new(id, config));
+        drop(registry);
+        tokio::spawn(control_valves(controllers, id.to_owned(), state_file));
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn delete_controller(
+        id: ControllerId,
+        controllers: Controllers,
+        state_file: Arc<StateFile>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut registry = controllers.write().await;
+        if !registry.contains_key(&id) {
+            return Err(warp::reject::not_found());
+        }
+        state_file.remove(&id).await?;
+        let controller = registry.remove(&id).unwrap();
+        drop(registry);
+        // Lets the executor notice it is gone
+        controller.wakeup.notify_one();
+        Ok(StatusCode::OK)
+    }
+
+    pub async fn set_location(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: LocationParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.location = Some(Location::new(params.latitude, params.longitude)?);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    /// Logs the moments the schedules would exceed the concurrency limit,
+    /// the executor queues the valves but the user should know about it.
+    fn warn_about_conflicts(config: &ControllerConfig) {
+        for conflict in config.concurrency_conflicts(&config.now()) {
+            tracing::warn!(
+                "Valves {:?} are scheduled at the same time at {}, only {} may be open",
+                conflict.valves,
+                conflict.time,
+                config.max_concurrent_open().unwrap_or_default()
+            );
+        }
+    }
+
+    pub async fn set_role(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: RoleParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.set_role(valve_number, params.role)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn rename_valve(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: RenameParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .set_name(&params.name)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn set_limit(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: LimitParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.set_max_concurrent_open(params.max_concurrent_open)?;
+        warn_about_conflicts(&config);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn set_max_open(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: MaxOpenParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.set_max_open_minutes(params.max_open_minutes)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn set_valve_max_open(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: MaxOpenParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .set_max_open_minutes(params.max_open_minutes)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn set_rain_delay(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: RainDelayParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        match params.hours {
+            Some(hours) => {
+                let now = config.now();
+                config.delay_for_rain(hours, &now)?;
+            }
+            None => config.cancel_rain_delay(),
+        }
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn set_rain_skip(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: RainSkipParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.set_rain_skip_mm(params.rain_skip_mm)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn set_moisture_threshold(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: MoistureThresholdParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .set_moisture_threshold(params.moisture_threshold)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn add_sensor(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: SensorParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.add_sensor(Sensor::new(params.id.trim(), params.name))?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn delete_sensor(
+        controller: Controller,
+        id: SensorId,
+        state_file: Arc<StateFile>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.remove_sensor(&id)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::reply())
+    }
+
+    pub async fn set_sensor_valves(
+        controller: Controller,
+        id: SensorId,
+        state_file: Arc<StateFile>,
+        params: SensorValvesParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.set_sensor_valves(&id, params.valves()?)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn record_reading(
+        controller: Controller,
+        id: SensorId,
+        state_file: Arc<StateFile>,
+        params: ReadingParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let now = config.now();
+        config
+            .sensor_mut(&id)
+            .ok_or(Error::InvalidSensorId)?
+            .record(params.moisture, &now)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(StatusCode::OK)
+    }
+
+    pub async fn acknowledge_safety_events(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.acknowledge_safety_events();
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    impl TransportParams {
+        fn transport(self) -> TransportConfig {
+            match self.kind {
+                TransportKind::Http => TransportConfig::Http,
+                TransportKind::Mqtt => TransportConfig::Mqtt {
+                    host: self.host.trim().to_owned(),
+                    port: self.port.unwrap_or(1883),
+                    topic_prefix: self.topic_prefix.trim().to_owned(),
+                },
+                TransportKind::Simulated => TransportConfig::Simulated,
+            }
+        }
+    }
+
+    pub async fn set_transport(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: TransportParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.set_transport(params.transport())?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn set_water_budget(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: WaterBudgetParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.set_water_budget(params.water_budget.ok_or(Error::InvalidWaterBudget)?)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(Uri::from_static("/")))
+    }
+
+    pub async fn set_valve_water_budget(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: WaterBudgetParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .set_water_budget(params.water_budget)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
     pub async fn delete_valve(
+        controller: Controller,
         valve_number: ValveNumber,
-        config: ServerConfig,
+        state_file: Arc<StateFile>,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        let mut config = config.write().await;
+        let mut config = controller.config.write().await;
         if !config.remove_valve(valve_number) {
             return Err(warp::reject::custom(InvalidValveNumber {}));
         }
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
         Ok(warp::reply())
     }
     pub async fn add_duration(
+        controller: Controller,
         valve_number: ValveNumber,
-        config: ServerConfig,
+        state_file: Arc<StateFile>,
         params: TimetableParams,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        let mut config = config.write().await;
-        let duration = Duration::new(params.start_time, params.end_time)?;
+        let mut config = controller.config.write().await;
+        let duration = duration(params.start_time, params.end_time, params.run_minutes)?;
+        let site = config.site();
         config
             .get_mut(valve_number)
             .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))
             .and_then(|valve| {
                 valve
-                    .add_duration(&params.day, duration)
-                    .map_err(|_| warp::reject::custom(InvalidValveNumber {}))?;
-                Ok(warp::redirect(
-                    Uri::try_from(format!("/valves/{}", valve_number)).unwrap(),
-                ))
-            })
+                    .add_duration(&params.day, duration, &site)
+                    .map_err(|_| warp::reject::custom(InvalidValveNumber {}))
+            })?;
+        warn_about_conflicts(&config);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
     }
     pub async fn delete_duration(
+        controller: Controller,
         valve_number: ValveNumber,
-        config: ServerConfig,
+        state_file: Arc<StateFile>,
         params: TimetableParams,
     ) -> Result<impl warp::Reply, warp::Rejection> {
-        let mut config = config.write().await;
-        let duration = Duration::new(params.start_time, params.end_time)?;
+        let mut config = controller.config.write().await;
+        let duration = duration(params.start_time, params.end_time, params.run_minutes)?;
         config
             .get_mut(valve_number)
             .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))
             .and_then(|valve| {
                 valve
                     .remove_duration(&params.day, duration)
-                    .map_err(|_| warp::reject::custom(InvalidValveNumber {}))?;
-                Ok(warp::reply())
-            })
+                    .map_err(warp::reject::custom)
+            })?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::reply())
+    }
+
+    /// The entry described by a form, ending at `end_time` or after `run_minutes`.
+    fn duration(
+        start_time: TimeOfDay,
+        end_time: Option<TimeOfDay>,
+        run_minutes: Option<u32>,
+    ) -> Result<Duration, Error> {
+        match (end_time, run_minutes) {
+            (Some(end_time), _) => Duration::new(start_time, end_time),
+            (None, Some(run_minutes)) => Duration::with_run_length(start_time, run_minutes),
+            (None, None) => Err(Error::MissingDuration),
+        }
+    }
+
+    impl SensorValvesParams {
+        pub fn valves(&self) -> Result<Vec<ValveNumber>, Error> {
+            self.valves
+                .split(',')
+                .map(str::trim)
+                .filter(|valve_number| !valve_number.is_empty())
+                .map(|valve_number| valve_number.parse().map_err(|_| Error::InvalidValveNumber))
+                .collect()
+        }
+    }
+
+    impl CalendarParams {
+        fn duration(&self) -> Result<Duration, Error> {
+            let start_time = self.start_time.ok_or(Error::MissingDuration)?;
+            duration(start_time, self.end_time, self.run_minutes)
+        }
+    }
+
+    pub async fn add_calendar_entry(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: CalendarParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let site = config.site();
+        let valve = config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?;
+        match params.kind {
+            CalendarEntryKind::Skip => valve.skip_date(params.date),
+            CalendarEntryKind::Replace => {
+                valve.add_exception(params.date, params.duration()?, &site)?
+            }
+            CalendarEntryKind::Extra => {
+                valve.add_one_off(params.date, params.duration()?, &site)?
+            }
+        }
+        warn_about_conflicts(&config);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn delete_calendar_entry(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: CalendarParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let valve = config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?;
+        match params.kind {
+            CalendarEntryKind::Skip => valve.remove_exception(&params.date)?,
+            CalendarEntryKind::Replace => {
+                valve.remove_exception_duration(&params.date, params.duration()?)?
+            }
+            CalendarEntryKind::Extra => valve.remove_one_off(&params.date, params.duration()?)?,
+        }
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::reply())
+    }
+
+    pub async fn set_season(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: SeasonParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let season = Season::new(params.start, params.end, params.yearly)?;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .season = Some(season);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn delete_season(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .season = None;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::reply())
+    }
+
+    pub async fn set_schedule_kind(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: ScheduleKindParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .schedule_kind = params.kind;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn set_interval(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: IntervalParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let site = config.site();
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .interval_schedule_mut()
+            .set_interval(params.every_days, params.anchor, &site)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn add_interval_duration(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: IntervalTimetableParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let duration = duration(params.start_time, params.end_time, params.run_minutes)?;
+        let site = config.site();
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .interval_schedule_mut()
+            .add_entry(duration, &site)?;
+        warn_about_conflicts(&config);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!(
+                "/controllers/{}/valves/{}",
+                controller.id, valve_number
+            ))
+            .unwrap(),
+        ))
+    }
+
+    pub async fn delete_interval_duration(
+        controller: Controller,
+        valve_number: ValveNumber,
+        state_file: Arc<StateFile>,
+        params: IntervalTimetableParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let duration = duration(params.start_time, params.end_time, params.run_minutes)?;
+        config
+            .get_mut(valve_number)
+            .ok_or_else(|| warp::reject::custom(InvalidValveNumber {}))?
+            .interval_schedule_mut()
+            .remove_entry(duration)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::reply())
+    }
+
+    impl ProgramParams {
+        fn weekdays(&self) -> Vec<Weekday> {
+            IntoIterator::into_iter([
+                (Weekday::Mon, self.monday),
+                (Weekday::Tue, self.tuesday),
+                (Weekday::Wed, self.wednesday),
+                (Weekday::Thu, self.thursday),
+                (Weekday::Fri, self.friday),
+                (Weekday::Sat, self.saturday),
+                (Weekday::Sun, self.sunday),
+            ])
+            .filter(|(_, active)| *active)
+            .map(|(day, _)| day)
+            .collect()
+        }
+
+        fn apply(&self, program: &mut Program) {
+            program.name = self.name.clone();
+            program.start = self.start;
+            program.pause_minutes = self.pause_minutes.unwrap_or(0);
+            program.set_weekdays(self.weekdays());
+        }
+    }
+
+    pub async fn create_program(
+        controller: Controller,
+        state_file: Arc<StateFile>,
+        params: ProgramParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        let mut program = Program::new(&params.name, params.start);
+        params.apply(&mut program);
+        let id = config.push_program(program);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!("/controllers/{}/programs/{}", controller.id, id)).unwrap(),
+        ))
+    }
+
+    pub async fn render_program(
+        controller: Controller,
+        id: ProgramId,
+    ) -> Result<WithTemplate<serde_json::Value>, warp::Rejection> {
+        let controller_config = controller.config.read().await;
+        let program = controller_config
+            .program(id)
+            .ok_or_else(|| warp::reject::custom(Error::InvalidProgramId))?;
+        Ok(WithTemplate {
+            name: "program",
+            value: json!(ProgramData::from(
+                &controller.id,
+                program,
+                &controller_config
+            )),
+        })
+    }
+
+    pub async fn delete_program(
+        controller: Controller,
+        id: ProgramId,
+        state_file: Arc<StateFile>,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.remove_program(id)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::reply())
+    }
+
+    pub async fn update_program(
+        controller: Controller,
+        id: ProgramId,
+        state_file: Arc<StateFile>,
+        params: ProgramParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        params.apply(config.program_mut(id).ok_or(Error::InvalidProgramId)?);
+        warn_about_conflicts(&config);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!("/controllers/{}/programs/{}", controller.id, id)).unwrap(),
+        ))
+    }
+
+    pub async fn add_program_step(
+        controller: Controller,
+        id: ProgramId,
+        state_file: Arc<StateFile>,
+        params: ProgramStepParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config.add_program_step(
+            id,
+            ProgramStep {
+                valve_number: params.valve_number,
+                run_minutes: params.run_minutes,
+            },
+        )?;
+        warn_about_conflicts(&config);
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::redirect(
+            Uri::try_from(format!("/controllers/{}/programs/{}", controller.id, id)).unwrap(),
+        ))
+    }
+
+    pub async fn delete_program_step(
+        controller: Controller,
+        id: ProgramId,
+        state_file: Arc<StateFile>,
+        params: ProgramStepIndexParams,
+    ) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut config = controller.config.write().await;
+        config
+            .program_mut(id)
+            .ok_or(Error::InvalidProgramId)?
+            .remove_step(params.index)?;
+        state_file
+            .save_or_roll_back(&controller.id, &mut config)
+            .await?;
+        controller.wakeup.notify_one();
+        Ok(warp::reply())
     }
 }
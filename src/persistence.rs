@@ -0,0 +1,272 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+use crate::datamodel::{ControllerConfig, ControllerId, Error};
+
+/// The id a state file from before there were several controllers is loaded as.
+pub const DEFAULT_CONTROLLER: &str = "default";
+
+#[derive(Serialize, Deserialize)]
+struct Stored<T> {
+    controllers: T,
+}
+
+#[derive(Debug, Default)]
+struct Saved {
+    /// The last saved state of every controller. Controllers are saved one
+    /// at a time under their own lock, the others are taken from here.
+    controllers: BTreeMap<ControllerId, serde_json::Value>,
+    /// Controllers removed since the start. Their executor or a handler may
+    /// still be about to save them, that must not bring them back.
+    removed: HashSet<ControllerId>,
+}
+
+/// The file the `ControllerConfig`s of all controllers are kept in between
+/// restarts.
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    saved: Mutex<Saved>,
+}
+
+impl StateFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        StateFile {
+            path: path.into(),
+            saved: Mutex::new(Saved::default()),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Reads the stored configs, returning `None` if nothing has been saved yet.
+    pub fn load(&mut self) -> Result<Option<BTreeMap<ControllerId, ControllerConfig>>, Error> {
+        let content = match std::fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let value: serde_json::Value = serde_json::from_slice(&content)?;
+        let controllers: BTreeMap<ControllerId, ControllerConfig> =
+            if value.get("controllers").is_some() {
+                serde_json::from_value::<Stored<_>>(value)?.controllers
+            } else {
+                // Written before there were several controllers
+                let config = serde_json::from_value(value)?;
+                IntoIterator::into_iter([(DEFAULT_CONTROLLER.to_owned(), config)]).collect()
+            };
+        let saved = &mut self.saved.get_mut().controllers;
+        for (id, config) in &controllers {
+            saved.insert(id.clone(), serde_json::to_value(config)?);
+        }
+        Ok(Some(controllers))
+    }
+
+    /// Saves the config of the controller `id` together with the last saved
+    /// state of all others. Fails for a controller that was removed.
+    pub async fn save(&self, id: &str, config: &ControllerConfig) -> Result<(), Error> {
+        let mut saved = self.saved.lock().await;
+        if saved.removed.contains(id) {
+            return Err(Error::RemovedController(id.to_owned()));
+        }
+        self.store(&mut saved.controllers, id, config).await
+    }
+
+    /// Saves a new controller, its id may have been used by a removed one.
+    pub async fn create(&self, id: &str, config: &ControllerConfig) -> Result<(), Error> {
+        let mut saved = self.saved.lock().await;
+        self.store(&mut saved.controllers, id, config).await?;
+        saved.removed.remove(id);
+        Ok(())
+    }
+
+    async fn store(
+        &self,
+        controllers: &mut BTreeMap<ControllerId, serde_json::Value>,
+        id: &str,
+        config: &ControllerConfig,
+    ) -> Result<(), Error> {
+        let previous = controllers.insert(id.to_owned(), serde_json::to_value(config)?);
+        if let Err(e) = self.write(controllers).await {
+            // Keep what is actually in the file
+            match previous {
+                Some(previous) => controllers.insert(id.to_owned(), previous),
+                None => controllers.remove(id),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Saves `config` like `save`, but sets it back to its last saved state
+    /// if that fails. Handlers change the config before saving it, this
+    /// keeps a change that wasn't saved from taking effect anyway.
+    pub async fn save_or_roll_back(
+        &self,
+        id: &str,
+        config: &mut ControllerConfig,
+    ) -> Result<(), Error> {
+        let error = match self.save(id, config).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if let Some(saved) = self.saved.lock().await.controllers.get(id) {
+            match serde_json::from_value::<ControllerConfig>(saved.clone()) {
+                Ok(mut restored) => {
+                    restored.keep_runtime_state(config);
+                    *config = restored;
+                }
+                Err(e) => tracing::error!("Failed to roll back controller {}: {}", id, e),
+            }
+        }
+        Err(error)
+    }
+
+    /// Drops the controller `id` from the file, later saves of it fail.
+    pub async fn remove(&self, id: &str) -> Result<(), Error> {
+        let mut saved = self.saved.lock().await;
+        let previous = saved.controllers.remove(id);
+        if let Err(e) = self.write(&saved.controllers).await {
+            if let Some(previous) = previous {
+                saved.controllers.insert(id.to_owned(), previous);
+            }
+            return Err(e);
+        }
+        saved.removed.insert(id.to_owned());
+        Ok(())
+    }
+
+    /// Writes to a temporary file next to the state file and renames it
+    /// into place, so a crash never leaves a half written file.
+    async fn write(
+        &self,
+        controllers: &BTreeMap<ControllerId, serde_json::Value>,
+    ) -> Result<(), Error> {
+        let content = serde_json::to_vec_pretty(&Stored { controllers })?;
+        let tmp_path = self.tmp_path();
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let mut file_name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        file_name.push(".tmp");
+        self.path.with_file_name(file_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StateFile, DEFAULT_CONTROLLER};
+    use crate::datamodel::{
+        AutomationStatus, ControllerConfig, Duration, Error, Valve, ValveStatus,
+    };
+    use chrono::{NaiveTime, Weekday};
+    use reqwest::Url;
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let path = std::env::temp_dir().join(format!("state_{}.json", std::process::id()));
+        let mut state_file = StateFile::new(&path);
+        assert!(state_file.load().unwrap().is_none());
+
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        let mut valve = Valve::new("beet", 3);
+        valve
+            .set_automation_status(AutomationStatus::Scheduled, &config.now())
+            .unwrap();
+        let duration =
+            Duration::new(NaiveTime::from_hms(6, 0, 0), NaiveTime::from_hms(6, 30, 0)).unwrap();
+        valve
+            .add_duration(&Weekday::Tue, duration, &config.site())
+            .unwrap();
+        config.push(valve);
+        state_file.save("garten", &config).await.unwrap();
+        state_file
+            .save("balkon", &ControllerConfig::new(config.address.clone()))
+            .await
+            .unwrap();
+
+        let loaded = StateFile::new(&path).load().unwrap().unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), vec!["balkon", "garten"]);
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            serde_json::to_value(&loaded["garten"]).unwrap()
+        );
+
+        // A file with a single controller is loaded as the default one
+        std::fs::write(&path, serde_json::to_vec(&config).unwrap()).unwrap();
+        let loaded = StateFile::new(&path).load().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), vec![DEFAULT_CONTROLLER]);
+    }
+
+    #[test]
+    fn test_corrupt_file() {
+        let path = std::env::temp_dir().join(format!("corrupt_{}.json", std::process::id()));
+        std::fs::write(&path, "{ not json").unwrap();
+        let result = StateFile::new(&path).load();
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_removed() {
+        let path = std::env::temp_dir().join(format!("removed_{}.json", std::process::id()));
+        let state_file = StateFile::new(&path);
+        let config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        state_file.create("garten", &config).await.unwrap();
+        state_file.create("balkon", &config).await.unwrap();
+        state_file.remove("garten").await.unwrap();
+
+        // An executor that was about to save it doesn't bring it back
+        assert!(matches!(
+            state_file.save("garten", &config).await,
+            Err(Error::RemovedController(_))
+        ));
+        let loaded = StateFile::new(&path).load().unwrap().unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), vec!["balkon"]);
+
+        // A new controller may take the id again
+        state_file.create("garten", &config).await.unwrap();
+        state_file.save("garten", &config).await.unwrap();
+        let loaded = StateFile::new(&path).load().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.keys().collect::<Vec<_>>(), vec!["balkon", "garten"]);
+    }
+
+    #[tokio::test]
+    async fn test_roll_back() {
+        let dir = std::env::temp_dir().join(format!("roll_back_{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let state_file = StateFile::new(dir.join("state.json"));
+        let mut config = ControllerConfig::new(Url::parse("https://localhost:4040").unwrap());
+        config.push(Valve::new("beet", 3));
+        state_file.save("garten", &config).await.unwrap();
+
+        // The file can't be written anymore
+        std::fs::remove_dir_all(&dir).unwrap();
+        config.get_mut(3).unwrap().reported_status = Some(ValveStatus::Open);
+        config.push(Valve::new("rasen", 4));
+        assert!(state_file
+            .save_or_roll_back("garten", &mut config)
+            .await
+            .is_err());
+        assert!(config.get(4).is_none());
+        assert_eq!(
+            config.get(3).unwrap().reported_status,
+            Some(ValveStatus::Open)
+        );
+    }
+}
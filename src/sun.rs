@@ -0,0 +1,72 @@
+//! Offline sunrise and sunset times following the sunrise equation used by
+//! NOAA, accurate to about a minute outside of the polar regions.
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+/// Julian date of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.0;
+/// Julian date of the unix epoch
+const UNIX_EPOCH: f64 = 2_440_587.5;
+/// Altitude of the sun's centre at sunrise, accounts for refraction and its radius
+const SUNRISE_ALTITUDE: f64 = -0.833;
+/// Axial tilt of the earth
+const OBLIQUITY: f64 = 23.4397;
+
+/// Sunrise and sunset on `date` at the given position in degrees, east and
+/// north being positive. `None` during polar day or night.
+pub fn sunrise_sunset(
+    date: NaiveDate,
+    latitude: f64,
+    longitude: f64,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let days = (date - NaiveDate::from_ymd(2000, 1, 1)).num_days() as f64;
+    // Mean solar noon
+    let noon = days + 0.0008 - longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = J2000 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * OBLIQUITY.to_radians().sin()).asin();
+
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = (SUNRISE_ALTITUDE.to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+    Some((
+        from_julian(transit - hour_angle),
+        from_julian(transit + hour_angle),
+    ))
+}
+
+fn from_julian(julian: f64) -> DateTime<Utc> {
+    let seconds = ((julian - UNIX_EPOCH) * 86_400.0).round() as i64;
+    Utc.timestamp(seconds, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sunrise_sunset;
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    #[test]
+    fn test_berlin() {
+        let (sunrise, sunset) =
+            sunrise_sunset(NaiveDate::from_ymd(2021, 6, 21), 52.52, 13.405).unwrap();
+        // Published times are 04:43 and 21:33 CEST
+        let expected_sunrise = Utc.ymd(2021, 6, 21).and_hms(2, 43, 0);
+        let expected_sunset = Utc.ymd(2021, 6, 21).and_hms(19, 33, 0);
+        assert!((sunrise - expected_sunrise).num_minutes().abs() <= 2);
+        assert!((sunset - expected_sunset).num_minutes().abs() <= 2);
+
+        // The sun doesn't set at the north cape in midsummer
+        assert!(sunrise_sunset(NaiveDate::from_ymd(2021, 6, 21), 71.17, 25.78).is_none());
+    }
+}
@@ -0,0 +1,327 @@
+//! How the executor talks to a controller: switching a valve, reading back
+//! its state and checking whether the controller can be reached at all.
+
+use crate::datamodel::{Error, ValveNumber, ValveStatus};
+use futures::future::BoxFuture;
+use reqwest::{Client, Url};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+/// How long a request to a controller's HTTP API may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before connecting to the broker again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long to wait for the controller to report a valve it was just told to switch.
+const STATE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the reported states are checked while waiting.
+const STATE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Switches the valves of one controller.
+pub trait ValveTransport: Send + Sync {
+    fn set(
+        &self,
+        valve_number: ValveNumber,
+        status: &ValveStatus,
+    ) -> BoxFuture<'_, Result<(), Error>>;
+    /// The state the controller reports for the valve.
+    fn read(&self, valve_number: ValveNumber) -> BoxFuture<'_, Result<ValveStatus, Error>>;
+    /// Fails if the controller can't be reached, commands aren't sent then.
+    fn health(&self) -> BoxFuture<'_, Result<(), Error>>;
+}
+
+/// Which transport a controller is driven with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "kind")]
+pub enum TransportConfig {
+    /// `PUT` and `GET` of `open`/`closed` on `/valves/:id` at the controller's address.
+    #[default]
+    Http,
+    /// Commands go to `<topic_prefix>/valves/:id/set`, the controller
+    /// reports on `<topic_prefix>/valves/:id/state`.
+    Mqtt {
+        host: String,
+        port: u16,
+        topic_prefix: String,
+    },
+    /// Keeps the valve states in memory, for tests and demos.
+    Simulated,
+}
+
+impl TransportConfig {
+    /// Creates the transport for the controller `id` at `address`.
+    pub fn build(&self, id: &str, address: &Url) -> Arc<dyn ValveTransport> {
+        match self {
+            TransportConfig::Http => Arc::new(HttpTransport::new(address.clone())),
+            TransportConfig::Mqtt {
+                host,
+                port,
+                topic_prefix,
+            } => Arc::new(MqttTransport::new(id, host, *port, topic_prefix)),
+            TransportConfig::Simulated => Arc::new(SimulatedTransport::default()),
+        }
+    }
+}
+
+fn payload(status: &ValveStatus) -> &'static str {
+    match status {
+        ValveStatus::Open => "open",
+        ValveStatus::Close => "closed",
+    }
+}
+
+fn parse_payload(payload: &str) -> Result<ValveStatus, Error> {
+    match payload.trim() {
+        "open" => Ok(ValveStatus::Open),
+        "closed" => Ok(ValveStatus::Close),
+        _ => Err(Error::InvalidControllerResponse(payload.to_owned())),
+    }
+}
+
+/// Talks to the controller's HTTP API.
+pub struct HttpTransport {
+    client: Client,
+    address: Url,
+}
+
+impl HttpTransport {
+    pub fn new(address: Url) -> Self {
+        // A controller that accepts the connection and then stalls would
+        // otherwise hold up its executor for good
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to set up the HTTP client");
+        HttpTransport { client, address }
+    }
+
+    fn valve_url(&self, valve_number: ValveNumber) -> Result<Url, Error> {
+        self.address
+            .join("/valves/")
+            .and_then(|url| url.join(&valve_number.to_string()))
+            .map_err(Error::InvalidAddress)
+    }
+}
+
+impl ValveTransport for HttpTransport {
+    fn set(
+        &self,
+        valve_number: ValveNumber,
+        status: &ValveStatus,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let body = payload(status);
+        Box::pin(async move {
+            let response = self
+                .client
+                .put(self.valve_url(valve_number)?)
+                .body(body)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(Error::ControllerStatus(response.status()));
+            }
+            Ok(())
+        })
+    }
+
+    fn read(&self, valve_number: ValveNumber) -> BoxFuture<'_, Result<ValveStatus, Error>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(self.valve_url(valve_number)?)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(Error::ControllerStatus(response.status()));
+            }
+            parse_payload(&response.text().await?)
+        })
+    }
+
+    /// Any response at all means the controller is up.
+    fn health(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            self.client.get(self.address.clone()).send().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Publishes commands to an MQTT broker and keeps the states the controller
+/// reports back.
+pub struct MqttTransport {
+    client: AsyncClient,
+    topic_prefix: String,
+    connected: Arc<AtomicBool>,
+    reported: Arc<Mutex<HashMap<ValveNumber, ValveStatus>>>,
+    event_loop: JoinHandle<()>,
+}
+
+impl MqttTransport {
+    pub fn new(id: &str, host: &str, port: u16, topic_prefix: &str) -> Self {
+        let mut options = MqttOptions::new(format!("sprenkler-controller-{}", id), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        let (client, mut event_loop) = AsyncClient::new(options, 64);
+        let topic_prefix = topic_prefix.trim_end_matches('/').to_owned();
+        let connected = Arc::new(AtomicBool::new(false));
+        let reported = Arc::new(Mutex::new(HashMap::new()));
+
+        let state_topic = format!("{}/valves/+/state", topic_prefix);
+        let valves_prefix = format!("{}/valves/", topic_prefix);
+        let event_loop = {
+            let client = client.clone();
+            let connected = connected.clone();
+            let reported = reported.clone();
+            tokio::spawn(async move {
+                loop {
+                    match event_loop.poll().await {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            connected.store(true, Ordering::Relaxed);
+                            // Subscriptions don't survive a reconnect
+                            if let Err(e) = client.subscribe(&state_topic, QoS::AtLeastOnce).await {
+                                tracing::warn!("Failed to subscribe to {}: {}", state_topic, e);
+                            }
+                        }
+                        Ok(Event::Incoming(Packet::Publish(publish))) => {
+                            let valve_number = publish
+                                .topic
+                                .strip_prefix(&valves_prefix)
+                                .and_then(|rest| rest.strip_suffix("/state"))
+                                .and_then(|number| number.parse().ok());
+                            let status = std::str::from_utf8(&publish.payload)
+                                .ok()
+                                .and_then(|payload| parse_payload(payload).ok());
+                            if let (Some(valve_number), Some(status)) = (valve_number, status) {
+                                reported.lock().unwrap().insert(valve_number, status);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            if connected.swap(false, Ordering::Relaxed) {
+                                tracing::warn!("Lost the connection to the MQTT broker: {}", e);
+                            }
+                            sleep(RECONNECT_DELAY).await;
+                        }
+                    }
+                }
+            })
+        };
+        MqttTransport {
+            client,
+            topic_prefix,
+            connected,
+            reported,
+            event_loop,
+        }
+    }
+
+    fn check_connected(&self) -> Result<(), Error> {
+        if self.connected.load(Ordering::Relaxed) {
+            Ok(())
+        } else {
+            Err(Error::Mqtt("not connected to the broker".to_owned()))
+        }
+    }
+}
+
+impl Drop for MqttTransport {
+    fn drop(&mut self) {
+        self.event_loop.abort();
+    }
+}
+
+impl ValveTransport for MqttTransport {
+    fn set(
+        &self,
+        valve_number: ValveNumber,
+        status: &ValveStatus,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let topic = format!("{}/valves/{}/set", self.topic_prefix, valve_number);
+        let body = payload(status);
+        Box::pin(async move {
+            self.check_connected()?;
+            // What the controller reported so far is out of date now
+            self.reported.lock().unwrap().remove(&valve_number);
+            self.client
+                .publish(topic, QoS::AtLeastOnce, false, body)
+                .await
+                .map_err(|e| Error::Mqtt(e.to_string()))
+        })
+    }
+
+    /// Waits for a fresh report if the valve was switched since its last one.
+    fn read(&self, valve_number: ValveNumber) -> BoxFuture<'_, Result<ValveStatus, Error>> {
+        Box::pin(async move {
+            let deadline = tokio::time::Instant::now() + STATE_TIMEOUT;
+            loop {
+                self.check_connected()?;
+                if let Some(status) = self.reported.lock().unwrap().get(&valve_number) {
+                    return Ok(status.clone());
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(Error::Mqtt(format!(
+                        "valve {} hasn't reported yet",
+                        valve_number
+                    )));
+                }
+                sleep(STATE_POLL_INTERVAL).await;
+            }
+        })
+    }
+
+    /// Gives a transport that was just created the time to connect.
+    fn health(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let deadline = tokio::time::Instant::now() + STATE_TIMEOUT;
+            while self.check_connected().is_err() && tokio::time::Instant::now() < deadline {
+                sleep(STATE_POLL_INTERVAL).await;
+            }
+            self.check_connected()
+        })
+    }
+}
+
+/// A controller that only exists in memory, valves it wasn't told about are
+/// closed.
+#[derive(Default)]
+pub struct SimulatedTransport {
+    valves: Mutex<HashMap<ValveNumber, ValveStatus>>,
+}
+
+impl ValveTransport for SimulatedTransport {
+    fn set(
+        &self,
+        valve_number: ValveNumber,
+        status: &ValveStatus,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        tracing::info!(
+            "Simulated valve {} is now {}",
+            valve_number,
+            payload(status)
+        );
+        self.valves
+            .lock()
+            .unwrap()
+            .insert(valve_number, status.clone());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn read(&self, valve_number: ValveNumber) -> BoxFuture<'_, Result<ValveStatus, Error>> {
+        let status = self
+            .valves
+            .lock()
+            .unwrap()
+            .get(&valve_number)
+            .cloned()
+            .unwrap_or(ValveStatus::Close);
+        Box::pin(async { Ok(status) })
+    }
+
+    fn health(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
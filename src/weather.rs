@@ -0,0 +1,217 @@
+//! Scales the run lengths of the day with the weather reported by a
+//! `WeatherProvider` and skips watering after heavy rain.
+
+use crate::datamodel::{Controllers, Error};
+use crate::persistence::StateFile;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::future::BoxFuture;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// How often the provider is asked for a new report.
+const POLL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Temperature at which the run lengths are left as they are.
+const REFERENCE_TEMPERATURE: f64 = 20.0;
+/// Percent more water per degree above the reference temperature.
+const PERCENT_PER_DEGREE: f64 = 4.0;
+/// Percent less water per millimetre of rain.
+const PERCENT_PER_MM_RAIN: f64 = 10.0;
+/// The adjustment never goes beyond this percentage.
+const MAX_PERCENT: f64 = 200.0;
+
+/// Recent rainfall and the expected maximum temperature.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WeatherReport {
+    /// Rain over the last 24 hours.
+    pub rain_mm: f64,
+    pub max_temperature: f64,
+}
+
+/// How the weather changes watering on `date`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct WeatherAdjustment {
+    pub date: NaiveDate,
+    /// Percentage the run lengths are scaled with on top of the water budgets.
+    pub percent: u32,
+    /// Scheduled watering is suspended for the whole day.
+    pub skip: bool,
+}
+
+impl WeatherAdjustment {
+    /// Every degree above the reference temperature adds water, every
+    /// millimetre of rain takes some away. `rain_skip_mm` is the rainfall
+    /// from which on the day is skipped altogether.
+    pub fn from_report(
+        report: &WeatherReport,
+        date: NaiveDate,
+        rain_skip_mm: Option<f64>,
+    ) -> WeatherAdjustment {
+        let percent = 100.0 + (report.max_temperature - REFERENCE_TEMPERATURE) * PERCENT_PER_DEGREE
+            - report.rain_mm.max(0.0) * PERCENT_PER_MM_RAIN;
+        WeatherAdjustment {
+            date,
+            percent: percent.clamp(0.0, MAX_PERCENT).round() as u32,
+            skip: rain_skip_mm.is_some_and(|threshold| report.rain_mm >= threshold),
+        }
+    }
+}
+
+/// The last report and what was made of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WeatherDecision {
+    pub time: DateTime<Utc>,
+    pub report: WeatherReport,
+    pub adjustment: WeatherAdjustment,
+}
+
+/// Where weather reports come from.
+pub trait WeatherProvider: Send + Sync {
+    fn report(&self) -> BoxFuture<'_, Result<WeatherReport, Error>>;
+}
+
+/// Fetches a `WeatherReport` as JSON from `url`.
+pub struct HttpWeatherProvider {
+    client: Client,
+    url: Url,
+}
+
+impl HttpWeatherProvider {
+    pub fn new(url: Url) -> Self {
+        HttpWeatherProvider {
+            client: Client::new(),
+            url,
+        }
+    }
+}
+
+impl WeatherProvider for HttpWeatherProvider {
+    fn report(&self) -> BoxFuture<'_, Result<WeatherReport, Error>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(self.url.clone())
+                .timeout(std::time::Duration::from_secs(10))
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(response.json().await?)
+        })
+    }
+}
+
+/// Reads a `WeatherReport` from a JSON file, for testing or for reports
+/// put in place by some other tool.
+pub struct FileWeatherProvider {
+    path: PathBuf,
+}
+
+impl FileWeatherProvider {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileWeatherProvider { path: path.into() }
+    }
+}
+
+impl WeatherProvider for FileWeatherProvider {
+    fn report(&self) -> BoxFuture<'_, Result<WeatherReport, Error>> {
+        Box::pin(async move {
+            let content = tokio::fs::read(&self.path).await?;
+            Ok(serde_json::from_slice(&content)?)
+        })
+    }
+}
+
+/// The provider configured through `WEATHER_URL` or `WEATHER_FILE`, if any.
+pub fn provider_from_env() -> Result<Option<Box<dyn WeatherProvider>>, Error> {
+    if let Ok(url) = std::env::var("WEATHER_URL") {
+        let url = Url::parse(&url).map_err(Error::InvalidAddress)?;
+        return Ok(Some(Box::new(HttpWeatherProvider::new(url))));
+    }
+    Ok(std::env::var("WEATHER_FILE")
+        .ok()
+        .map(|path| Box::new(FileWeatherProvider::new(path)) as Box<dyn WeatherProvider>))
+}
+
+/// Asks `provider` for a report every `POLL_INTERVAL` and adjusts today's
+/// watering of every controller to it. Failed polls keep the last decision,
+/// it only applies to the day it was made for anyway.
+pub async fn poll_weather(
+    provider: Box<dyn WeatherProvider>,
+    controllers: Controllers,
+    state_file: Arc<StateFile>,
+) -> ! {
+    loop {
+        match provider.report().await {
+            Ok(report) => {
+                let snapshot: Vec<_> = controllers.read().await.values().cloned().collect();
+                for controller in snapshot {
+                    let mut config = controller.config.write().await;
+                    let now = config.now();
+                    let adjustment = WeatherAdjustment::from_report(
+                        &report,
+                        now.naive_local().date(),
+                        config.rain_skip_mm(),
+                    );
+                    tracing::info!(
+                        "Weather {:?}: watering {} at {}%{}",
+                        report,
+                        controller.id,
+                        adjustment.percent,
+                        if adjustment.skip { ", skipped" } else { "" }
+                    );
+                    config.set_weather(WeatherDecision {
+                        time: now.with_timezone(&Utc),
+                        report,
+                        adjustment,
+                    });
+                    // Holding the registry keeps the controller from being
+                    // removed while it is written back
+                    let registry = controllers.read().await;
+                    if !controller.is_registered(&registry) {
+                        continue;
+                    }
+                    if let Err(e) = state_file.save(&controller.id, &config).await {
+                        tracing::error!("Failed to save the state file: {}", e);
+                    }
+                    drop(registry);
+                    controller.wakeup.notify_one();
+                }
+            }
+            Err(e) => tracing::warn!("Failed to get a weather report: {}", e),
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileWeatherProvider, WeatherAdjustment, WeatherProvider, WeatherReport};
+    use chrono::NaiveDate;
+
+    #[tokio::test]
+    async fn test_adjustment() {
+        let path = std::env::temp_dir().join(format!("weather_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"rain_mm": 2.0, "max_temperature": 30.0}"#).unwrap();
+        let report = FileWeatherProvider::new(&path).report().await;
+        std::fs::remove_file(&path).unwrap();
+        let report = report.unwrap();
+        let date = NaiveDate::from_ymd(2021, 9, 13);
+
+        // 10 degrees above the reference add 40%, the rain takes 20% away
+        let adjustment = WeatherAdjustment::from_report(&report, date, None);
+        assert_eq!(adjustment.percent, 120);
+        assert!(!adjustment.skip);
+        assert!(WeatherAdjustment::from_report(&report, date, Some(2.0)).skip);
+
+        let downpour = WeatherReport {
+            rain_mm: 25.0,
+            max_temperature: 15.0,
+        };
+        assert_eq!(
+            WeatherAdjustment::from_report(&downpour, date, None).percent,
+            0
+        );
+    }
+}
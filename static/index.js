@@ -1,6 +1,6 @@
 'use strict';
-function updateStatus(valve_number, new_status) {
-    let request = new Request(`/valves/${valve_number}/status`,
+function updateStatus(controller, valve_number, new_status) {
+    let request = new Request(`/controllers/${controller}/valves/${valve_number}/status`,
         {
             method: 'POST',
             headers: {
@@ -14,8 +14,30 @@ function updateStatus(valve_number, new_status) {
         .catch((e) => console.log(e))
 }
 
-function deleteButton(valve_number) {
-    let request = new Request(`/valves/${valve_number}/`,
+function deleteButton(controller, valve_number) {
+    let request = new Request(`/controllers/${controller}/valves/${valve_number}/`,
+        {
+            method: 'DELETE',
+            referrerPolicy: 'no-referrer',
+        })
+    fetch(request)
+        .then(() => window.location.reload())
+        .catch((e) => console.log(e))
+}
+
+function deleteSensor(controller, id) {
+    let request = new Request(`/controllers/${controller}/sensors/${id}`,
+        {
+            method: 'DELETE',
+            referrerPolicy: 'no-referrer',
+        })
+    fetch(request)
+        .then(() => window.location.reload())
+        .catch((e) => console.log(e))
+}
+
+function deleteController(controller) {
+    let request = new Request(`/controllers/${controller}`,
         {
             method: 'DELETE',
             referrerPolicy: 'no-referrer',
@@ -27,12 +49,23 @@ function deleteButton(valve_number) {
 
 document.addEventListener('DOMContentLoaded', (event) => {
     for (let radioButton of document.getElementsByClassName("automation_status_radio")) {
+        let controller = radioButton.dataset.controller;
         let valve_number = radioButton.dataset.valve_number;
         let value = radioButton.value;
-        radioButton.addEventListener("click", (elem, ev) => { updateStatus(valve_number, value) })
+        radioButton.addEventListener("click", (elem, ev) => { updateStatus(controller, valve_number, value) })
+    }
+    for (let button of document.getElementsByClassName("timed_run_button")) {
+        let until = () => new Date(Date.now() + button.dataset.minutes * 60 * 1000).toISOString();
+        button.addEventListener("click", (elem, ev) => updateStatus(button.dataset.controller, button.dataset.valve_number, { ForceOpenUntil: until() }))
+    }
+    for (let button of document.getElementsByClassName("sensor_delete_button")) {
+        button.addEventListener("click", (elem, ev) => deleteSensor(button.dataset.controller, button.dataset.id))
     }
     for (let button of document.getElementsByClassName("valve_delete_button")) {
 
-        button.addEventListener("click", (elem, ev) => deleteButton(button.dataset.valve_number) )
+        button.addEventListener("click", (elem, ev) => deleteButton(button.dataset.controller, button.dataset.valve_number) )
+    }
+    for (let button of document.getElementsByClassName("controller_delete_button")) {
+        button.addEventListener("click", (elem, ev) => deleteController(button.dataset.controller))
     }
 });
\ No newline at end of file
@@ -0,0 +1,35 @@
+'use strict';
+
+function deleteStep(index) {
+    let request = new Request(document.documentURI + `/steps`,
+        {
+            method: 'DELETE',
+            headers: {
+                "Content-Type" : "application/json"
+            },
+            referrerPolicy: 'no-referrer',
+            body: JSON.stringify({index: Number(index)})
+        })
+    fetch(request)
+        .then(() => window.location.reload())
+        .catch((e) => console.log(e))
+}
+
+function deleteProgram() {
+    let request = new Request(document.documentURI,
+        {
+            method: 'DELETE',
+            referrerPolicy: 'no-referrer',
+        })
+    fetch(request)
+        .then(() => window.location.assign("/"))
+        .catch((e) => console.log(e))
+}
+
+document.addEventListener('DOMContentLoaded', (_event) => {
+    for (let button of document.getElementsByClassName("step_delete_button")) {
+        button.addEventListener("click", (_elem, _ev) => deleteStep(button.dataset.index))
+    }
+    document.getElementById("program_delete_button")
+        .addEventListener("click", (_elem, _ev) => deleteProgram())
+});
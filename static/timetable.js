@@ -1,6 +1,6 @@
 'use strict';
 
-function deleteSchedule(day, start_time, end_time) {
+function deleteSchedule(day, start_time, end_time, run_minutes) {
     let request = new Request(document.documentURI +`/timetable`,
         {
             method: 'DELETE',
@@ -8,7 +8,48 @@ function deleteSchedule(day, start_time, end_time) {
                 "Content-Type" : "application/json"
             },
             referrerPolicy: 'no-referrer',
-            body: JSON.stringify({day, start_time, end_time})
+            body: JSON.stringify({day, start_time, end_time, run_minutes})
+        })
+    fetch(request)
+        .then(() => window.location.reload())
+        .catch((e) => console.log(e))
+}
+
+function deleteCalendarEntry(date, kind, start_time, end_time, run_minutes) {
+    let request = new Request(document.documentURI + `/calendar`,
+        {
+            method: 'DELETE',
+            headers: {
+                "Content-Type" : "application/json"
+            },
+            referrerPolicy: 'no-referrer',
+            body: JSON.stringify({date, kind, start_time, end_time, run_minutes})
+        })
+    fetch(request)
+        .then(() => window.location.reload())
+        .catch((e) => console.log(e))
+}
+
+function deleteIntervalEntry(start_time, end_time, run_minutes) {
+    let request = new Request(document.documentURI + `/interval/timetable`,
+        {
+            method: 'DELETE',
+            headers: {
+                "Content-Type" : "application/json"
+            },
+            referrerPolicy: 'no-referrer',
+            body: JSON.stringify({start_time, end_time, run_minutes})
+        })
+    fetch(request)
+        .then(() => window.location.reload())
+        .catch((e) => console.log(e))
+}
+
+function deleteSeason() {
+    let request = new Request(document.documentURI + `/season`,
+        {
+            method: 'DELETE',
+            referrerPolicy: 'no-referrer',
         })
     fetch(request)
         .then(() => window.location.reload())
@@ -18,7 +59,21 @@ function deleteSchedule(day, start_time, end_time) {
 document.addEventListener('DOMContentLoaded', (_event) => {
     for (let button of document.getElementsByClassName("schedule_delete_button")) {
         button.addEventListener("click", (elem, _ev) => {
-            deleteSchedule(button.dataset.day, button.dataset.begin, button.dataset.end)
+            deleteSchedule(button.dataset.day, button.dataset.begin, button.dataset.end, button.dataset.run_minutes)
+        })
+    }
+    for (let button of document.getElementsByClassName("calendar_delete_button")) {
+        button.addEventListener("click", (elem, _ev) => {
+            deleteCalendarEntry(button.dataset.date, button.dataset.kind, button.dataset.begin, button.dataset.end, button.dataset.run_minutes)
         })
     }
+    for (let button of document.getElementsByClassName("interval_delete_button")) {
+        button.addEventListener("click", (elem, _ev) => {
+            deleteIntervalEntry(button.dataset.begin, button.dataset.end, button.dataset.run_minutes)
+        })
+    }
+    let seasonButton = document.getElementById("season_delete_button")
+    if (seasonButton) {
+        seasonButton.addEventListener("click", (_elem, _ev) => deleteSeason())
+    }
 });
\ No newline at end of file